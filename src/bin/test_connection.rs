@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
 
-use joinmarket_directory_checker::connection::socks5_connect;
+use joinmarket_directory_checker::connection::{
+    socks5_connect, socks5_resolve, socks5_resolve_ptr,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[tokio::main]
@@ -81,5 +83,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("✗ Failed to connect to tor: {}", e);
         }
     }
+
+    // Test 5: Resolve a hostname through the proxy (Tor RESOLVE extension)
+    println!("\n=== Test 5: Tor RESOLVE ===");
+    match socks5_resolve(proxy_addr, "example.com").await {
+        Ok(address) => {
+            println!("✓ Resolved example.com to {} via SOCKS5", address);
+        }
+        Err(e) => {
+            println!("✗ Failed to resolve example.com: {}", e);
+        }
+    }
+
+    // Test 6: Reverse-resolve an address through the proxy (Tor RESOLVE_PTR extension)
+    println!("\n=== Test 6: Tor RESOLVE_PTR ===");
+    match socks5_resolve_ptr(proxy_addr, "8.8.8.8".parse()?).await {
+        Ok(hostname) => {
+            println!("✓ Reverse-resolved 8.8.8.8 to {} via SOCKS5", hostname);
+        }
+        Err(e) => {
+            println!("✗ Failed to reverse-resolve 8.8.8.8: {}", e);
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::time::sleep;
+
+use crate::tor_control::{Result, TorControl, parse_key_values};
+use crate::types::{CircuitRecord, Relay, TorControlConfig};
+
+/// How long circuits and streams are remembered after their last event
+const RETENTION: Duration = Duration::from_secs(15 * 60);
+
+/// Wait before reconnecting after the control connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Follows Tor's `CIRC` and `STREAM` events to tell which circuits carried a probe
+///
+/// Streams are matched by the local address of the probe's connection to the SOCKS port, which
+/// Tor reports as `SOURCE_ADDR`. For onion services the introduction and rendezvous circuits
+/// built for the service are matched as well, so failed connections still show a path.
+#[derive(Clone, Default)]
+pub struct CircuitTracker {
+    state: Arc<Mutex<TrackerState>>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    circuits: HashMap<String, TrackedCircuit>,
+    streams: HashMap<String, TrackedStream>,
+}
+
+struct TrackedCircuit {
+    record: CircuitRecord,
+    /// Onion service the circuit was built for (`REND_QUERY`)
+    rend_query: Option<String>,
+    updated: Instant,
+}
+
+struct TrackedStream {
+    source_addr: Option<SocketAddr>,
+    circuit_id: Option<String>,
+    updated: Instant,
+}
+
+impl CircuitTracker {
+    /// Follow events on the configured control port in the background, reconnecting as needed
+    pub fn follow(&self, config: TorControlConfig) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(error) = tracker.follow_events(&config).await {
+                    println!("🧅 Circuit tracking interrupted: {}", error);
+                }
+                sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+
+    async fn follow_events(&self, config: &TorControlConfig) -> Result<()> {
+        let mut control = TorControl::connect(config).await?;
+        control.set_events(&["CIRC", "STREAM"]).await?;
+
+        // Circuits built before we subscribed only show up in circuit-status
+        let circuit_status = control.get_info(&["circuit-status"]).await?;
+        if let Some(circuits) = circuit_status.get("circuit-status") {
+            for line in circuits.lines() {
+                self.handle_circuit(line);
+            }
+        }
+
+        loop {
+            let event = control.next_event().await?;
+            let Some(first_line) = event.lines.first() else {
+                continue;
+            };
+            if let Some(circuit) = first_line.text.strip_prefix("CIRC ") {
+                self.handle_circuit(circuit);
+            } else if let Some(stream) = first_line.text.strip_prefix("STREAM ") {
+                self.handle_stream(stream);
+            }
+        }
+    }
+
+    /// Apply `<CircuitID> <CircStatus> [<Path>] [KEY=VALUE ...]`
+    pub fn handle_circuit(&self, text: &str) {
+        let mut words = text.split(' ');
+        let (Some(id), Some(status)) = (words.next(), words.next()) else {
+            return;
+        };
+        if id.is_empty() || status.is_empty() {
+            return;
+        }
+        let rest: Vec<&str> = words.collect();
+        let path = rest
+            .first()
+            .filter(|word| word.starts_with('$'))
+            .map(|path| path.split(',').map(Relay::parse).collect::<Vec<_>>());
+        let attributes = parse_key_values(&rest.join(" "));
+
+        let mut state = self.state.lock().unwrap();
+        state.prune(Instant::now());
+        let circuit = state
+            .circuits
+            .entry(id.to_string())
+            .or_insert_with(|| TrackedCircuit {
+                record: CircuitRecord {
+                    id: id.to_string(),
+                    purpose: None,
+                    status: String::new(),
+                    path: Vec::new(),
+                },
+                rend_query: None,
+                updated: Instant::now(),
+            });
+        circuit.record.status = status.to_string();
+        if let Some(path) = path {
+            circuit.record.path = path;
+        }
+        if let Some(purpose) = attributes.get("PURPOSE") {
+            circuit.record.purpose = Some(purpose.clone());
+        }
+        if let Some(rend_query) = attributes.get("REND_QUERY") {
+            circuit.rend_query = Some(rend_query.clone());
+        }
+        circuit.updated = Instant::now();
+    }
+
+    /// Apply `<StreamID> <StreamStatus> <CircuitID> <Target> [KEY=VALUE ...]`
+    pub fn handle_stream(&self, text: &str) {
+        let mut words = text.split(' ');
+        let (Some(id), Some(_status), Some(circuit_id), Some(_target)) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return;
+        };
+        if id.is_empty() || circuit_id.is_empty() {
+            return;
+        }
+        let attributes = parse_key_values(&words.collect::<Vec<_>>().join(" "));
+
+        let mut state = self.state.lock().unwrap();
+        state.prune(Instant::now());
+        let stream = state
+            .streams
+            .entry(id.to_string())
+            .or_insert_with(|| TrackedStream {
+                source_addr: None,
+                circuit_id: None,
+                updated: Instant::now(),
+            });
+        if let Some(source_addr) = attributes
+            .get("SOURCE_ADDR")
+            .and_then(|source_addr| source_addr.parse().ok())
+        {
+            stream.source_addr = Some(source_addr);
+        }
+        // Circuit 0 means the stream isn't attached (yet)
+        if circuit_id != "0" {
+            stream.circuit_id = Some(circuit_id.to_string());
+        }
+        stream.updated = Instant::now();
+    }
+
+    /// Circuits that carried a probe started at `since`
+    ///
+    /// `source_addr` is the probe's local address towards the SOCKS port and `service_id` the
+    /// onion service it connected to, if any.
+    pub fn circuits_for(
+        &self,
+        source_addr: Option<SocketAddr>,
+        service_id: Option<&str>,
+        since: Instant,
+    ) -> Vec<CircuitRecord> {
+        let state = self.state.lock().unwrap();
+        let mut circuits: Vec<CircuitRecord> = Vec::new();
+
+        let stream_circuit = source_addr.and_then(|source_addr| {
+            state
+                .streams
+                .values()
+                .filter(|stream| stream.source_addr == Some(source_addr) && stream.updated >= since)
+                .find_map(|stream| stream.circuit_id.as_ref())
+        });
+        if let Some(circuit) = stream_circuit.and_then(|id| state.circuits.get(id)) {
+            circuits.push(circuit.record.clone());
+        }
+
+        if let Some(service_id) = service_id {
+            let mut service_circuits: Vec<&TrackedCircuit> = state
+                .circuits
+                .values()
+                .filter(|circuit| {
+                    circuit.rend_query.as_deref() == Some(service_id) && circuit.updated >= since
+                })
+                .filter(|circuit| !circuits.contains(&circuit.record))
+                .collect();
+            service_circuits.sort_by_key(|circuit| circuit.record.id.parse::<u64>().ok());
+            circuits.extend(
+                service_circuits
+                    .into_iter()
+                    .map(|circuit| circuit.record.clone()),
+            );
+        }
+
+        circuits
+    }
+}
+
+impl TrackerState {
+    /// Forget circuits and streams without events in the [`RETENTION`] before `now`
+    fn prune(&mut self, now: Instant) {
+        let Some(cutoff) = now.checked_sub(RETENTION) else {
+            return;
+        };
+        self.circuits.retain(|_, circuit| circuit.updated >= cutoff);
+        self.streams.retain(|_, stream| stream.updated >= cutoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE_ID: &str = "qkmfpxqmxhx7m3dsizb3pj6wgaiz24qdlbjc2zqsjmrffbtkpv2a";
+
+    fn relay(fingerprint: &str, nickname: &str) -> Relay {
+        Relay {
+            fingerprint: fingerprint.to_string(),
+            nickname: Some(nickname.to_string()),
+        }
+    }
+
+    fn circuit(tracker: &CircuitTracker, id: &str) -> Option<CircuitRecord> {
+        let state = tracker.state.lock().unwrap();
+        state.circuits.get(id).map(|circuit| circuit.record.clone())
+    }
+
+    #[test]
+    fn circuit_events_update_status_path_and_purpose() {
+        let tracker = CircuitTracker::default();
+        tracker.handle_circuit("5 LAUNCHED BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL");
+        tracker.handle_circuit(
+            "5 BUILT $AAAA~guard,$BBBB~middle,$CCCC=exit BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL",
+        );
+
+        assert_eq!(
+            circuit(&tracker, "5"),
+            Some(CircuitRecord {
+                id: "5".to_string(),
+                purpose: Some("GENERAL".to_string()),
+                status: "BUILT".to_string(),
+                path: vec![
+                    relay("AAAA", "guard"),
+                    relay("BBBB", "middle"),
+                    relay("CCCC", "exit")
+                ],
+            })
+        );
+
+        // A closing circuit keeps the path it was built with
+        tracker.handle_circuit("5 CLOSED REASON=FINISHED");
+        let closed = circuit(&tracker, "5").unwrap();
+        assert_eq!(closed.status, "CLOSED");
+        assert_eq!(closed.path.len(), 3);
+
+        tracker.handle_circuit("6 FAILED $AAAA~guard PURPOSE=HS_CLIENT_INTRO REASON=TIMEOUT");
+        let failed = circuit(&tracker, "6").unwrap();
+        assert_eq!(failed.status, "FAILED");
+        assert_eq!(failed.purpose.as_deref(), Some("HS_CLIENT_INTRO"));
+        assert_eq!(failed.path, vec![relay("AAAA", "guard")]);
+    }
+
+    #[test]
+    fn streams_and_onion_circuits_map_to_the_probe() {
+        let tracker = CircuitTracker::default();
+        let since = Instant::now();
+        let source_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        tracker.handle_circuit(&format!(
+            "9 BUILT $AAAA~guard PURPOSE=HS_CLIENT_INTRO HS_STATE=HSCI_DONE REND_QUERY={SERVICE_ID}"
+        ));
+        tracker.handle_circuit(&format!(
+            "8 BUILT $BBBB~guard PURPOSE=HS_CLIENT_REND HS_STATE=HSCR_JOINED REND_QUERY={SERVICE_ID}"
+        ));
+        tracker.handle_circuit("3 BUILT $CCCC~guard PURPOSE=GENERAL");
+        // Not attached yet, then attached to the rendezvous circuit
+        tracker.handle_stream(&format!(
+            "21 NEW 0 {SERVICE_ID}.onion:5222 SOURCE_ADDR=127.0.0.1:40000 PURPOSE=USER"
+        ));
+        tracker.handle_stream(&format!("21 SENTCONNECT 8 {SERVICE_ID}.onion:5222"));
+
+        let ids = |circuits: Vec<CircuitRecord>| {
+            circuits
+                .into_iter()
+                .map(|circuit| circuit.id)
+                .collect::<Vec<_>>()
+        };
+        // The stream's own circuit first, then the service's other circuits in order
+        assert_eq!(
+            ids(tracker.circuits_for(Some(source_addr), Some(SERVICE_ID), since)),
+            vec!["8", "9"]
+        );
+        assert_eq!(
+            ids(tracker.circuits_for(Some(source_addr), None, since)),
+            vec!["8"]
+        );
+        assert!(
+            tracker
+                .circuits_for(Some("127.0.0.1:40001".parse().unwrap()), None, since)
+                .is_empty()
+        );
+        // Events from before the probe started belong to an earlier probe
+        assert!(
+            tracker
+                .circuits_for(Some(source_addr), Some(SERVICE_ID), Instant::now())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_ignored() {
+        let tracker = CircuitTracker::default();
+        for line in ["", "5", " ", " BUILT"] {
+            tracker.handle_circuit(line);
+        }
+        for line in ["", "21", "21 NEW", "21 NEW 0", " NEW 0 target"] {
+            tracker.handle_stream(line);
+        }
+        {
+            let state = tracker.state.lock().unwrap();
+            assert!(state.circuits.is_empty());
+            assert!(state.streams.is_empty());
+        }
+
+        // An unreadable source address doesn't match any probe
+        tracker.handle_circuit("4 BUILT $AAAA~guard");
+        tracker.handle_stream("22 SENTCONNECT 4 192.0.2.1:5222 SOURCE_ADDR=garbage");
+        let state = tracker.state.lock().unwrap();
+        assert_eq!(state.streams["22"].source_addr, None);
+        assert_eq!(state.streams["22"].circuit_id.as_deref(), Some("4"));
+    }
+
+    #[test]
+    fn stream_events_prune_old_entries() {
+        let Some(stale) = Instant::now().checked_sub(RETENTION + Duration::from_secs(1)) else {
+            return;
+        };
+        let tracker = CircuitTracker::default();
+        {
+            let mut state = tracker.state.lock().unwrap();
+            state.streams.insert(
+                "1".to_string(),
+                TrackedStream {
+                    source_addr: None,
+                    circuit_id: None,
+                    updated: stale,
+                },
+            );
+            state.circuits.insert(
+                "1".to_string(),
+                TrackedCircuit {
+                    record: CircuitRecord {
+                        id: "1".to_string(),
+                        purpose: None,
+                        status: "BUILT".to_string(),
+                        path: Vec::new(),
+                    },
+                    rend_query: None,
+                    updated: stale,
+                },
+            );
+        }
+
+        tracker.handle_stream("2 NEW 0 192.0.2.1:5222");
+        let state = tracker.state.lock().unwrap();
+        assert_eq!(state.streams.keys().collect::<Vec<_>>(), vec!["2"]);
+        assert!(state.circuits.is_empty());
+    }
+}
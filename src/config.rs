@@ -1,52 +1,460 @@
 use std::fs;
+use std::net::{AddrParseError, SocketAddr};
 use std::path::Path;
 
-use crate::types::Config;
+use thiserror::Error;
+
+use crate::onion::{OnionAddress, OnionAddressError};
+use crate::types::{CheckMode, Config, FreshIdentity};
+
+/// Beyond this a jittered check could land right next to the previous one
+const MAX_JITTER_PERCENT: u8 = 50;
+
+/// More retries would keep a check of a dead endpoint going for ages
+const MAX_RETRIES: u32 = 10;
+
+/// Check results aren't kept any longer than this
+const MAX_LATENCY_WINDOW_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("[[endpoints]] entry #{index} ({name:?}): invalid onion address {address:?}: {source}")]
+    InvalidOnionAddress {
+        index: usize,
+        name: String,
+        address: String,
+        source: OnionAddressError,
+    },
+    #[error("proxy {label:?}: invalid address: {source}")]
+    InvalidProxyAddress {
+        label: String,
+        source: AddrParseError,
+    },
+    #[error("endpoints are checked through a proxy, but no [proxy] or [[proxies]] is configured")]
+    NoProxy,
+    #[error(
+        "[[references]] are checked through a proxy, but no [proxy] or [[proxies]] is configured"
+    )]
+    ReferencesWithoutProxy,
+    #[error(
+        "[[references]] entry #{index} ({name:?}): invalid onion address {address:?}: {source}"
+    )]
+    InvalidReferenceAddress {
+        index: usize,
+        name: String,
+        address: String,
+        source: OnionAddressError,
+    },
+    #[error("fresh_identity = \"newnym\" needs a [tor_control] section")]
+    NewnymWithoutControlPort,
+    #[error("[[endpoints]] entry #{index} ({name:?}): {field} must be greater than 0")]
+    ZeroDuration {
+        index: usize,
+        name: String,
+        field: &'static str,
+    },
+    #[error("[monitoring]: {0} must be at least 1")]
+    ZeroThreshold(&'static str),
+    #[error("[monitoring]: retries must be at most {MAX_RETRIES}")]
+    TooManyRetries,
+    #[error("[monitoring]: jitter_percent must be at most {MAX_JITTER_PERCENT}")]
+    JitterTooLarge,
+    #[error(
+        "[monitoring]: latency_window_seconds must be at most {MAX_LATENCY_WINDOW_SECONDS} (30 days)"
+    )]
+    LatencyWindowTooLong,
+    #[error("[monitoring]: anomaly_deviations must be greater than 0")]
+    InvalidAnomalyDeviations,
+    #[error(
+        "[monitoring]: min_check_interval_seconds must not be more than max_check_interval_seconds"
+    )]
+    IntervalBounds,
+    #[error("[server.onion_service] needs a [tor_control] section")]
+    OnionServiceWithoutControlPort,
+    #[error("[tor_control]: set exactly one of address or unix_socket")]
+    TorControlAddress,
+    #[error(
+        "[[endpoints]] entry #{index} ({name:?}): client_auth_key only applies to onion services"
+    )]
+    ClientAuthWithoutOnion { index: usize, name: String },
+    #[error(
+        "[[endpoints]] entry #{index} ({name:?}): client_auth_key needs a [tor_control] section"
+    )]
+    ClientAuthWithoutControlPort { index: usize, name: String },
+    #[error("[tor_control]: invalid address {address:?}: {source}")]
+    InvalidTorControlAddress {
+        address: String,
+        source: AddrParseError,
+    },
+}
 
 pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn std::error::Error>> {
     let content = fs::read_to_string(path)?;
     let config: Config = toml::from_str(&content)?;
+    validate_config(&config)?;
     Ok(config)
 }
 
+/// Check the parts of a configuration that serde can't, such as onion address checksums
+pub fn validate_config(config: &Config) -> Result<(), ConfigError> {
+    let proxies = config.all_proxies();
+    for proxy in &proxies {
+        proxy
+            .socket_addr()
+            .map_err(|source| ConfigError::InvalidProxyAddress {
+                label: proxy.label(),
+                source,
+            })?;
+    }
+
+    let uses_proxy = config
+        .endpoints
+        .iter()
+        .any(|endpoint| endpoint.mode == CheckMode::Proxy);
+    if uses_proxy && proxies.is_empty() {
+        return Err(ConfigError::NoProxy);
+    }
+    if !config.references.is_empty() && proxies.is_empty() {
+        return Err(ConfigError::ReferencesWithoutProxy);
+    }
+    for (index, reference) in config.references.iter().enumerate() {
+        OnionAddress::parse(&reference.address).map_err(|source| {
+            ConfigError::InvalidReferenceAddress {
+                index: index + 1,
+                name: reference.name.clone(),
+                address: reference.address.clone(),
+                source,
+            }
+        })?;
+    }
+
+    if let Some(tor_control) = &config.tor_control {
+        match (&tor_control.address, &tor_control.unix_socket) {
+            (Some(address), None) => {
+                address.parse::<SocketAddr>().map_err(|source| {
+                    ConfigError::InvalidTorControlAddress {
+                        address: address.clone(),
+                        source,
+                    }
+                })?;
+            }
+            (None, Some(_)) => {}
+            _ => return Err(ConfigError::TorControlAddress),
+        }
+    }
+
+    if config.monitoring.fresh_identity == FreshIdentity::Newnym && config.tor_control.is_none() {
+        return Err(ConfigError::NewnymWithoutControlPort);
+    }
+
+    for (field, threshold) in [
+        (
+            "failures_before_offline",
+            config.monitoring.failures_before_offline,
+        ),
+        (
+            "successes_before_online",
+            config.monitoring.successes_before_online,
+        ),
+    ] {
+        if threshold == Some(0) {
+            return Err(ConfigError::ZeroThreshold(field));
+        }
+    }
+    if config.monitoring.retries > MAX_RETRIES {
+        return Err(ConfigError::TooManyRetries);
+    }
+    if config.monitoring.max_concurrent_checks == Some(0) {
+        return Err(ConfigError::ZeroThreshold("max_concurrent_checks"));
+    }
+    if config.monitoring.flapping_changes == Some(0) {
+        return Err(ConfigError::ZeroThreshold("flapping_changes"));
+    }
+    if config.monitoring.flapping_window_seconds == Some(0) {
+        return Err(ConfigError::ZeroThreshold("flapping_window_seconds"));
+    }
+    if config.monitoring.latency_window_seconds == Some(0) {
+        return Err(ConfigError::ZeroThreshold("latency_window_seconds"));
+    }
+    if config
+        .monitoring
+        .latency_window_seconds
+        .is_some_and(|seconds| seconds > MAX_LATENCY_WINDOW_SECONDS)
+    {
+        return Err(ConfigError::LatencyWindowTooLong);
+    }
+    if config
+        .monitoring
+        .anomaly_deviations
+        .is_some_and(|deviations| deviations.is_nan() || deviations <= 0.0)
+    {
+        return Err(ConfigError::InvalidAnomalyDeviations);
+    }
+    for (field, value) in [
+        (
+            "check_interval_seconds",
+            Some(config.monitoring.check_interval_seconds),
+        ),
+        (
+            "connection_timeout_seconds",
+            Some(config.monitoring.connection_timeout_seconds),
+        ),
+        (
+            "min_check_interval_seconds",
+            config.monitoring.min_check_interval_seconds,
+        ),
+        (
+            "max_check_interval_seconds",
+            config.monitoring.max_check_interval_seconds,
+        ),
+        (
+            "max_checks_per_minute",
+            config
+                .monitoring
+                .max_checks_per_minute
+                .map(|value| value as u64),
+        ),
+    ] {
+        if value == Some(0) {
+            return Err(ConfigError::ZeroThreshold(field));
+        }
+    }
+    if let (Some(min), Some(max)) = (
+        config.monitoring.min_check_interval_seconds,
+        config.monitoring.max_check_interval_seconds,
+    ) && min > max
+    {
+        return Err(ConfigError::IntervalBounds);
+    }
+    if config.monitoring.jitter_percent > MAX_JITTER_PERCENT {
+        return Err(ConfigError::JitterTooLarge);
+    }
+
+    if config.server.onion_service.is_some() && config.tor_control.is_none() {
+        return Err(ConfigError::OnionServiceWithoutControlPort);
+    }
+
+    for (index, endpoint) in config.endpoints.iter().enumerate() {
+        if OnionAddress::is_onion(&endpoint.address) {
+            OnionAddress::parse(&endpoint.address).map_err(|source| {
+                ConfigError::InvalidOnionAddress {
+                    index: index + 1,
+                    name: endpoint.name.clone(),
+                    address: endpoint.address.clone(),
+                    source,
+                }
+            })?;
+        }
+
+        for (field, seconds) in [
+            ("check_interval_seconds", endpoint.check_interval_seconds),
+            (
+                "connection_timeout_seconds",
+                endpoint.connection_timeout_seconds,
+            ),
+        ] {
+            if seconds == Some(0) {
+                return Err(ConfigError::ZeroDuration {
+                    index: index + 1,
+                    name: endpoint.name.clone(),
+                    field,
+                });
+            }
+        }
+
+        if endpoint.client_auth_key.is_some() {
+            let index = index + 1;
+            let name = endpoint.name.clone();
+            if !OnionAddress::is_onion(&endpoint.address) {
+                return Err(ConfigError::ClientAuthWithoutOnion { index, name });
+            }
+            if config.tor_control.is_none() {
+                return Err(ConfigError::ClientAuthWithoutControlPort { index, name });
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn create_default_config() -> Config {
-    use crate::types::{Endpoint, MonitoringConfig, ProxyConfig, ServerConfig};
+    use crate::types::{Endpoint, MonitoringConfig, ProxyConfig, ProxyMode, ServerConfig};
 
     Config {
         server: ServerConfig {
             host: "127.0.0.1".to_string(),
             port: 3000,
+            onion_service: None,
         },
-        proxy: ProxyConfig {
+        proxy: Some(ProxyConfig {
+            name: None,
             host: "127.0.0.1".to_string(),
             port: 9050,
-        },
+        }),
+        proxies: Vec::new(),
         monitoring: MonitoringConfig {
             check_interval_seconds: 30,
             connection_timeout_seconds: 10,
+            proxy_mode: ProxyMode::Failover,
+            fresh_identity: FreshIdentity::Off,
+            retries: 0,
+            retry_backoff_ms: None,
+            failures_before_offline: None,
+            successes_before_online: None,
+            max_concurrent_checks: None,
+            stagger_checks: false,
+            jitter_percent: 0,
+            degraded_response_ms: None,
+            flapping_changes: None,
+            flapping_window_seconds: None,
+            history_file: None,
+            latency_window_seconds: None,
+            anomaly_deviations: None,
+            min_check_interval_seconds: None,
+            max_check_interval_seconds: None,
+            max_checks_per_minute: None,
+            shutdown_grace_seconds: None,
         },
+        tor_control: None,
         endpoints: vec![
             Endpoint {
-                name: "Example Hidden Service".to_string(),
-                address: "example1234567890abcdef1234567890abcdef12345678.onion".to_string(),
-                port: 80,
+                name: "JoinMarket Directory Node 1".to_string(),
+                address: "g3hv4uynnmynqqq2mchf3fcm3yd46kfzmcdogejuckgwknwyq5ya6iad.onion"
+                    .to_string(),
+                port: 5222,
+                mode: CheckMode::Proxy,
+                handshake: false,
+                client_auth_key: None,
+                check_interval_seconds: None,
+                connection_timeout_seconds: None,
             },
             Endpoint {
-                name: "Another Service".to_string(),
-                address: "another1234567890abcdef1234567890abcdef12345678.onion".to_string(),
-                port: 8080,
+                name: "JoinMarket Directory Node 2".to_string(),
+                address: "3kxw6lf5vf6y26emzwgibzhrzhmhqiw6ekrek3nqfjjmhwznb2moonad.onion"
+                    .to_string(),
+                port: 5222,
+                mode: CheckMode::Proxy,
+                handshake: false,
+                client_auth_key: None,
+                check_interval_seconds: None,
+                connection_timeout_seconds: None,
             },
             Endpoint {
-                name: "HTTPS Service".to_string(),
-                address: "secure1234567890abcdef1234567890abcdef12345678.onion".to_string(),
-                port: 443,
+                name: "JoinMarket Directory Node 3".to_string(),
+                address: "bqlpq6ak24mwvuixixitift4yu42nxchlilrcqwk2ugn45tdclg42qid.onion"
+                    .to_string(),
+                port: 5222,
+                mode: CheckMode::Proxy,
+                handshake: false,
+                client_auth_key: None,
+                check_interval_seconds: None,
+                connection_timeout_seconds: None,
             },
             Endpoint {
                 name: "RoboSats".to_string(),
                 address: "robosatsy56bwqn56qyadmcxkx767hnabg4mihxlmgyt6if5gnuxvzad.onion"
                     .to_string(),
                 port: 80,
+                mode: CheckMode::Proxy,
+                handshake: false,
+                client_auth_key: None,
+                check_interval_seconds: None,
+                connection_timeout_seconds: None,
             },
         ],
+        references: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::ClientAuthKey;
+    use crate::types::{Endpoint, TorControlConfig};
+
+    #[test]
+    fn default_config_is_valid() {
+        validate_config(&create_default_config()).unwrap();
+    }
+
+    const CLIENT_AUTH_KEY: &str = "qkmfpxqmxhx7m3dsizb3pj6wgaiz24qdlbjc2zqsjmrffbtkpv2a";
+
+    fn endpoint_with_key(key: &str) -> Result<Endpoint, toml::de::Error> {
+        toml::from_str(&format!(
+            "name = \"hidden\"\naddress = \"example.onion\"\nport = 5222\nclient_auth_key = \"{key}\"\n"
+        ))
+    }
+
+    #[test]
+    fn client_auth_key_is_decoded_and_kept_out_of_debug_output() {
+        let endpoint = endpoint_with_key(CLIENT_AUTH_KEY).unwrap();
+        assert_eq!(
+            endpoint.client_auth_key,
+            Some(ClientAuthKey::parse(CLIENT_AUTH_KEY).unwrap())
+        );
+        assert!(!format!("{endpoint:?}").contains(CLIENT_AUTH_KEY));
+    }
+
+    #[test]
+    fn control_password_is_kept_out_of_debug_and_serialized_output() {
+        let tor_control: TorControlConfig =
+            toml::from_str("address = \"127.0.0.1:9051\"\npassword = \"hunter2\"\n").unwrap();
+        assert_eq!(tor_control.password.as_deref(), Some("hunter2"));
+        assert!(!format!("{tor_control:?}").contains("hunter2"));
+        assert!(
+            !serde_json::to_string(&tor_control)
+                .unwrap()
+                .contains("hunter2")
+        );
+    }
+
+    #[test]
+    fn invalid_client_auth_key_fails_to_load() {
+        let error = endpoint_with_key("x448:abc").unwrap_err();
+        assert!(error.to_string().contains("x448"), "{error}");
+    }
+
+    #[test]
+    fn readme_onion_addresses_are_valid() {
+        let addresses: Vec<&str> = include_str!("../README.md")
+            .lines()
+            .filter_map(|line| line.strip_prefix("address = \""))
+            .filter_map(|rest| rest.split_once('"'))
+            .map(|(address, _)| address)
+            .filter(|address| OnionAddress::is_onion(address))
+            .collect();
+        assert!(!addresses.is_empty());
+        for address in addresses {
+            assert!(OnionAddress::parse(address).is_ok(), "{address}");
+        }
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut config = create_default_config();
+        config.monitoring.retries = MAX_RETRIES;
+        validate_config(&config).unwrap();
+        config.monitoring.retries = MAX_RETRIES + 1;
+        assert!(matches!(
+            validate_config(&config),
+            Err(ConfigError::TooManyRetries)
+        ));
+    }
+
+    #[test]
+    fn zero_global_interval_or_timeout_is_rejected() {
+        let mut config = create_default_config();
+        config.monitoring.check_interval_seconds = 0;
+        assert!(matches!(
+            validate_config(&config),
+            Err(ConfigError::ZeroThreshold("check_interval_seconds"))
+        ));
+
+        let mut config = create_default_config();
+        config.monitoring.connection_timeout_seconds = 0;
+        assert!(matches!(
+            validate_config(&config),
+            Err(ConfigError::ZeroThreshold("connection_timeout_seconds"))
+        ));
     }
 }
@@ -1,9 +1,12 @@
 use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::types::CheckTimings;
+
 #[derive(Error, Debug)]
 pub enum Socks5Error {
     #[error("IO error: {0}")]
@@ -16,6 +19,10 @@ pub enum Socks5Error {
     InvalidResponse,
     #[error("Unsupported address type")]
     UnsupportedAddressType,
+    #[error("SOCKS5 resolve failed: {0}")]
+    ResolveFailed(String),
+    #[error("Onion service client authorization failed: {0}")]
+    ClientAuthorization(String),
 }
 
 pub type Result<T> = std::result::Result<T, Socks5Error>;
@@ -24,6 +31,27 @@ pub type Result<T> = std::result::Result<T, Socks5Error>;
 #[derive(Debug, Clone, Copy)]
 pub enum Command {
     Connect = 0x01,
+    /// Tor extension: resolve a hostname to an address without opening a stream
+    Resolve = 0xF0,
+    /// Tor extension: reverse-resolve an address to a hostname
+    ResolvePtr = 0xF1,
+}
+
+/// Address returned in the BND.ADDR field of a SOCKS5 reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BoundAddress {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+/// Username and password for SOCKS5 authentication (RFC 1929)
+///
+/// Tor doesn't check them, but with `IsolateSOCKSAuth` (on by default) streams opened with
+/// different credentials never share a circuit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Credentials {
+    pub username: String,
+    pub password: String,
 }
 
 /// SOCKS5 address types
@@ -47,29 +75,112 @@ pub async fn socks5_connect(
     proxy_addr: SocketAddr,
     target_host: &str,
     target_port: u16,
+) -> Result<TcpStream> {
+    socks5_connect_timed(
+        proxy_addr,
+        target_host,
+        target_port,
+        &mut CheckTimings::default(),
+    )
+    .await
+}
+
+/// Connect to a target through a SOCKS5 proxy, recording how long each step takes
+///
+/// Each phase's duration is written to `timings` as soon as it completes, so the
+/// completed phases are still available if the connection fails or times out.
+pub async fn socks5_connect_timed(
+    proxy_addr: SocketAddr,
+    target_host: &str,
+    target_port: u16,
+    timings: &mut CheckTimings,
+) -> Result<TcpStream> {
+    socks5_connect_authenticated(proxy_addr, target_host, target_port, None, timings).await
+}
+
+/// Like [`socks5_connect_timed`], authenticating with `credentials` when given
+pub async fn socks5_connect_authenticated(
+    proxy_addr: SocketAddr,
+    target_host: &str,
+    target_port: u16,
+    credentials: Option<&Socks5Credentials>,
+    timings: &mut CheckTimings,
 ) -> Result<TcpStream> {
     // Connect to the SOCKS5 proxy
+    let phase_start = Instant::now();
     let mut stream = TcpStream::connect(proxy_addr).await?;
+    timings.tcp_connect_ms = Some(phase_start.elapsed().as_millis() as u64);
+    timings.proxy_source_addr = stream.local_addr().ok();
 
-    // Step 1: Authentication negotiation (no auth only)
-    negotiate_no_auth(&mut stream).await?;
+    // Step 1: Authentication negotiation
+    let phase_start = Instant::now();
+    negotiate(&mut stream, credentials).await?;
+    timings.auth_ms = Some(phase_start.elapsed().as_millis() as u64);
 
     // Step 2: Send connection request
-    send_connect_request(&mut stream, target_host, target_port).await?;
+    let phase_start = Instant::now();
+    send_request(&mut stream, Command::Connect, target_host, target_port).await?;
 
     // Step 3: Read connection response
-    read_connect_response(&mut stream).await?;
+    read_reply(&mut stream).await?;
+    timings.connect_reply_ms = Some(phase_start.elapsed().as_millis() as u64);
 
     Ok(stream)
 }
 
-/// Negotiate no authentication with SOCKS5 proxy
-async fn negotiate_no_auth(stream: &mut TcpStream) -> Result<()> {
-    // Send authentication methods - only no auth
+/// Check that a SOCKS5 proxy accepts connections and completes the greeting
+pub async fn socks5_probe(proxy_addr: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(proxy_addr).await?;
+    negotiate(&mut stream, None).await
+}
+
+/// Resolve a hostname through Tor's SOCKS5 RESOLVE extension
+///
+/// The lookup is done by the Tor network, so it doesn't leak to the local resolver, and no
+/// stream is opened to the host.
+pub async fn socks5_resolve(proxy_addr: SocketAddr, hostname: &str) -> Result<IpAddr> {
+    match socks5_command(proxy_addr, Command::Resolve, hostname).await? {
+        BoundAddress::Ip(ip) => Ok(ip),
+        BoundAddress::Domain(_) => Err(Socks5Error::InvalidResponse),
+    }
+}
+
+/// Reverse-resolve an address through Tor's SOCKS5 RESOLVE_PTR extension
+pub async fn socks5_resolve_ptr(proxy_addr: SocketAddr, address: IpAddr) -> Result<String> {
+    match socks5_command(proxy_addr, Command::ResolvePtr, &address.to_string()).await? {
+        BoundAddress::Domain(hostname) => Ok(hostname),
+        BoundAddress::Ip(_) => Err(Socks5Error::InvalidResponse),
+    }
+}
+
+/// Run a single-reply command (RESOLVE or RESOLVE_PTR) and return the reply's bound address
+async fn socks5_command(
+    proxy_addr: SocketAddr,
+    command: Command,
+    target_host: &str,
+) -> Result<BoundAddress> {
+    let mut stream = TcpStream::connect(proxy_addr).await?;
+    negotiate(&mut stream, None).await?;
+    send_request(&mut stream, command, target_host, 0).await?;
+
+    read_reply(&mut stream).await.map_err(|error| match error {
+        Socks5Error::ConnectionFailed(reason) => Socks5Error::ResolveFailed(reason),
+        other => other,
+    })
+}
+
+/// Negotiate authentication with SOCKS5 proxy: none, or username/password with `credentials`
+async fn negotiate(stream: &mut TcpStream, credentials: Option<&Socks5Credentials>) -> Result<()> {
+    let method = match credentials {
+        Some(_) => 0x02, // Username/password
+        None => 0x00,    // No authentication
+    };
+
+    // Send authentication methods - only the one we want
     let request = vec![
         0x05, // SOCKS version 5
         0x01, // Number of methods
-        0x00, // No authentication
+        method,
     ];
 
     stream.write_all(&request).await?;
@@ -83,23 +194,47 @@ async fn negotiate_no_auth(stream: &mut TcpStream) -> Result<()> {
     }
 
     let selected_method = response[1];
-    if selected_method != 0x00 {
+    if selected_method != method {
         return Err(Socks5Error::AuthenticationFailed);
     }
 
+    if let Some(credentials) = credentials {
+        let username = credentials.username.as_bytes();
+        let password = credentials.password.as_bytes();
+        if username.len() > 255 || password.len() > 255 {
+            return Err(Socks5Error::AuthenticationFailed);
+        }
+
+        let mut request = vec![0x01, username.len() as u8]; // Subnegotiation version 1
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        stream.write_all(&request).await?;
+
+        let mut response = [0u8; 2];
+        stream.read_exact(&mut response).await?;
+        if response[0] != 0x01 {
+            return Err(Socks5Error::InvalidResponse);
+        }
+        if response[1] != 0x00 {
+            return Err(Socks5Error::AuthenticationFailed);
+        }
+    }
+
     Ok(())
 }
 
-/// Send CONNECT request to SOCKS5 proxy
-async fn send_connect_request(
+/// Send a request (CONNECT, RESOLVE or RESOLVE_PTR) to SOCKS5 proxy
+async fn send_request(
     stream: &mut TcpStream,
+    command: Command,
     target_host: &str,
     target_port: u16,
 ) -> Result<()> {
     let mut request = vec![
-        0x05,                   // SOCKS version
-        Command::Connect as u8, // Command
-        0x00,                   // Reserved
+        0x05,          // SOCKS version
+        command as u8, // Command
+        0x00,          // Reserved
     ];
 
     // Determine address type and encode address
@@ -131,8 +266,8 @@ async fn send_connect_request(
     Ok(())
 }
 
-/// Read and parse CONNECT response from SOCKS5 proxy
-async fn read_connect_response(stream: &mut TcpStream) -> Result<()> {
+/// Read and parse a reply from SOCKS5 proxy, returning the bound address
+async fn read_reply(stream: &mut TcpStream) -> Result<BoundAddress> {
     // Read fixed part of response
     let mut response = [0u8; 4];
     stream.read_exact(&mut response).await?;
@@ -180,16 +315,59 @@ async fn read_connect_response(stream: &mut TcpStream) -> Result<()> {
                 "Address type not supported".to_string(),
             ));
         }
+        // Tor's extended errors for onion services (SocksPort flag ExtendedErrors)
+        0xF0 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Onion service descriptor can not be found".to_string(),
+            ));
+        }
+        0xF1 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Onion service descriptor is invalid".to_string(),
+            ));
+        }
+        0xF2 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Onion service introduction failed".to_string(),
+            ));
+        }
+        0xF3 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Onion service rendezvous failed".to_string(),
+            ));
+        }
+        0xF4 => {
+            return Err(Socks5Error::ClientAuthorization(
+                "missing client authorization".to_string(),
+            ));
+        }
+        0xF5 => {
+            return Err(Socks5Error::ClientAuthorization(
+                "wrong client authorization".to_string(),
+            ));
+        }
+        0xF6 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Invalid onion service address".to_string(),
+            ));
+        }
+        0xF7 => {
+            return Err(Socks5Error::ConnectionFailed(
+                "Onion service introduction timed out".to_string(),
+            ));
+        }
         _ => return Err(Socks5Error::ConnectionFailed("Unknown error".to_string())),
     }
 
-    // Read bound address (we don't use it, but need to consume it)
+    // Read bound address (the resolved address for RESOLVE and RESOLVE_PTR)
     let address_type = response[3];
-    match address_type {
+    let bound_address = match address_type {
         0x01 => {
             // IPv4: 4 bytes + 2 bytes port
             let mut addr = [0u8; 6];
             stream.read_exact(&mut addr).await?;
+            let octets: [u8; 4] = addr[..4].try_into().expect("slice is 4 bytes");
+            BoundAddress::Ip(IpAddr::from(octets))
         }
         0x03 => {
             // Domain name: 1 byte length + domain + 2 bytes port
@@ -198,14 +376,73 @@ async fn read_connect_response(stream: &mut TcpStream) -> Result<()> {
             let len = len_buf[0] as usize;
             let mut addr = vec![0u8; len + 2];
             stream.read_exact(&mut addr).await?;
+            let domain = String::from_utf8(addr[..len].to_vec())
+                .map_err(|_| Socks5Error::InvalidResponse)?;
+            BoundAddress::Domain(domain)
         }
         0x04 => {
             // IPv6: 16 bytes + 2 bytes port
             let mut addr = [0u8; 18];
             stream.read_exact(&mut addr).await?;
+            let octets: [u8; 16] = addr[..16].try_into().expect("slice is 16 bytes");
+            BoundAddress::Ip(IpAddr::from(octets))
         }
         _ => return Err(Socks5Error::InvalidResponse),
+    };
+
+    Ok(bound_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Negotiate with credentials against a proxy that answers the method selection and then
+    /// sends `reply` to the username/password sub-negotiation
+    async fn negotiate_against(reply: [u8; 2]) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+            let mut request = [0u8; 9];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, *b"\x01\x04user\x02pw");
+            stream.write_all(&reply).await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let credentials = Socks5Credentials {
+            username: "user".to_string(),
+            password: "pw".to_string(),
+        };
+        let result = negotiate(&mut stream, Some(&credentials)).await;
+        proxy.await.unwrap();
+        result
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn credentials_accepted() {
+        assert!(negotiate_against([0x01, 0x00]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn credentials_rejected() {
+        assert!(matches!(
+            negotiate_against([0x01, 0x01]).await,
+            Err(Socks5Error::AuthenticationFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_sub_negotiation_version_is_invalid() {
+        assert!(matches!(
+            negotiate_against([0x05, 0x00]).await,
+            Err(Socks5Error::InvalidResponse)
+        ));
+    }
 }
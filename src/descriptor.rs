@@ -0,0 +1,291 @@
+use aes::Aes256;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::{Engine, alphabet};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Digest, Sha3_256, Shake256};
+use thiserror::Error;
+
+use crate::onion::OnionAddress;
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// Descriptor blobs are wrapped base64 that may or may not be padded
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+/// Ed25519 certificate extension carrying the key that signed the certificate
+const SIGNED_WITH_ED25519_KEY: u8 = 0x04;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    #[error("descriptor has no {0}")]
+    Missing(&'static str),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("{0} layer failed to decrypt (client authorization may be required)")]
+    MacMismatch(&'static str),
+}
+
+/// Count the introduction points listed in a v3 onion service descriptor
+///
+/// Decrypts both encryption layers as described in rend-spec-v3 section 2.5. The blinded key
+/// needed for that is taken from the descriptor's signing key certificate. Descriptors of
+/// services requiring client authorization can't be decrypted without the client's key.
+pub fn count_introduction_points(
+    descriptor: &str,
+    onion: &OnionAddress,
+) -> Result<usize, DescriptorError> {
+    let certificate = armored_object(descriptor, "descriptor-signing-key-cert")?;
+    let blinded_key = signing_key(&certificate)?;
+    let subcredential = subcredential(onion.public_key(), &blinded_key);
+    decrypt_introduction_points(descriptor, &blinded_key, &subcredential)
+}
+
+/// Count the introduction points once the descriptor's keys are known
+fn decrypt_introduction_points(
+    descriptor: &str,
+    blinded_key: &[u8; 32],
+    subcredential: &[u8; 32],
+) -> Result<usize, DescriptorError> {
+    let revision_counter = descriptor
+        .lines()
+        .find_map(|line| line.strip_prefix("revision-counter "))
+        .ok_or(DescriptorError::Missing("revision-counter"))?
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| DescriptorError::Invalid("revision-counter"))?;
+    let layer = LayerKeys {
+        blinded_key,
+        subcredential,
+        revision_counter,
+    };
+
+    let superencrypted = armored_object(descriptor, "superencrypted")?;
+    let first_layer = layer.decrypt(&superencrypted, b"hsdir-superencrypted-data", "outer")?;
+    let first_layer = String::from_utf8_lossy(&first_layer);
+
+    let encrypted = armored_object(&first_layer, "encrypted")?;
+    let second_layer = layer.decrypt(&encrypted, b"hsdir-encrypted-data", "inner")?;
+
+    Ok(String::from_utf8_lossy(&second_layer)
+        .lines()
+        .filter(|line| line.starts_with("introduction-point "))
+        .count())
+}
+
+struct LayerKeys<'a> {
+    blinded_key: &'a [u8; 32],
+    subcredential: &'a [u8; 32],
+    revision_counter: u64,
+}
+
+impl LayerKeys<'_> {
+    /// Decrypt `SALT | ENCRYPTED | MAC` after checking the MAC
+    fn decrypt(
+        &self,
+        blob: &[u8],
+        string_constant: &[u8],
+        name: &'static str,
+    ) -> Result<Vec<u8>, DescriptorError> {
+        if blob.len() < SALT_LEN + MAC_LEN {
+            return Err(DescriptorError::Invalid("encrypted layer"));
+        }
+        let (salt, rest) = blob.split_at(SALT_LEN);
+        let (encrypted, mac) = rest.split_at(rest.len() - MAC_LEN);
+
+        // keys = KDF(SECRET_DATA | N_hs_subcred | INT_8(revision_counter) | salt | STRING_CONSTANT)
+        let mut kdf = Shake256::default();
+        kdf.update(self.blinded_key);
+        kdf.update(self.subcredential);
+        kdf.update(&self.revision_counter.to_be_bytes());
+        kdf.update(salt);
+        kdf.update(string_constant);
+        let mut keys = [0u8; KEY_LEN + IV_LEN + MAC_LEN];
+        kdf.finalize_xof().read(&mut keys);
+        let (secret_key, rest) = keys.split_at(KEY_LEN);
+        let (secret_iv, mac_key) = rest.split_at(IV_LEN);
+
+        // D_MAC = H(mac_key_len | MAC_KEY | salt_len | SALT | ENCRYPTED)
+        let expected_mac = Sha3_256::new()
+            .chain_update((MAC_LEN as u64).to_be_bytes())
+            .chain_update(mac_key)
+            .chain_update((SALT_LEN as u64).to_be_bytes())
+            .chain_update(salt)
+            .chain_update(encrypted)
+            .finalize();
+        if expected_mac.as_slice() != mac {
+            return Err(DescriptorError::MacMismatch(name));
+        }
+
+        let mut plaintext = encrypted.to_vec();
+        Aes256Ctr::new(secret_key.into(), secret_iv.into()).apply_keystream(&mut plaintext);
+        Ok(plaintext)
+    }
+}
+
+/// N_hs_subcred = H("subcredential" | H("credential" | public-identity-key) | blinded-public-key)
+fn subcredential(identity_key: &[u8; 32], blinded_key: &[u8; 32]) -> [u8; 32] {
+    let credential = Sha3_256::new()
+        .chain_update(b"credential")
+        .chain_update(identity_key)
+        .finalize();
+    Sha3_256::new()
+        .chain_update(b"subcredential")
+        .chain_update(credential)
+        .chain_update(blinded_key)
+        .finalize()
+        .into()
+}
+
+/// The key that signed an ed25519 certificate, which for the descriptor signing key
+/// certificate is the service's blinded key
+fn signing_key(certificate: &[u8]) -> Result<[u8; 32], DescriptorError> {
+    const INVALID: DescriptorError = DescriptorError::Invalid("descriptor-signing-key-cert");
+
+    // VERSION | CERT_TYPE | EXPIRATION_DATE (4) | CERT_KEY_TYPE | CERTIFIED_KEY (32) | N_EXTENSIONS
+    let extension_count = *certificate.get(39).ok_or(INVALID)?;
+    let mut extensions = certificate.get(40..).ok_or(INVALID)?;
+
+    for _ in 0..extension_count {
+        // ExtLength (2) | ExtType | ExtFlags | ExtData
+        let [length_high, length_low, extension_type, _flags, rest @ ..] = extensions else {
+            return Err(INVALID);
+        };
+        let length = u16::from_be_bytes([*length_high, *length_low]) as usize;
+        let data = rest.get(..length).ok_or(INVALID)?;
+        if *extension_type == SIGNED_WITH_ED25519_KEY {
+            return data.try_into().map_err(|_| INVALID);
+        }
+        extensions = &rest[length..];
+    }
+
+    Err(DescriptorError::Missing(
+        "signing key in descriptor-signing-key-cert",
+    ))
+}
+
+/// Decode the `-----BEGIN ...-----` object following the line `keyword`
+fn armored_object(document: &str, keyword: &'static str) -> Result<Vec<u8>, DescriptorError> {
+    let mut lines = document
+        .lines()
+        .map(str::trim_end)
+        .skip_while(|line| *line != keyword)
+        .skip(1);
+    if !lines
+        .next()
+        .is_some_and(|line| line.starts_with("-----BEGIN "))
+    {
+        return Err(DescriptorError::Missing(keyword));
+    }
+
+    let encoded: String = lines
+        .take_while(|line| !line.starts_with("-----END "))
+        .collect();
+    BASE64
+        .decode(encoded)
+        .map_err(|_| DescriptorError::Invalid(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Descriptors from the test data of Arti's tor-netdoc crate, with the keys it publishes
+    // for them. The first one's service identity isn't known, only its subcredential.
+    const DESCRIPTOR: &str = include_str!("../testdata/hsdesc1.txt");
+    const BLINDED_KEY: &str = "43cc0d62fc6252f578705ca645a46109e265290343b1137e90189744b20b3f2d";
+    const SUBCREDENTIAL: &str = "78210a0d2c72bb7a0caf606bcd938b9a3696894fdddbc3b87d424753a7e3df37";
+
+    // The second one requires client authorization
+    const CLIENT_AUTH_DESCRIPTOR: &str = include_str!("../testdata/hsdesc2.txt");
+    const CLIENT_AUTH_IDENTITY: &str =
+        "781d978ce6ce9caa8bca306f53e82d2c993e5c91346625f1c151dcfc56d753d3";
+    const CLIENT_AUTH_BLINDED_KEY: &str =
+        "706628758208395d461aa0f460a5e76e7b828c66b5e794768592b451302e961d";
+    const CLIENT_AUTH_SUBCREDENTIAL: &str =
+        "24a133e905102bda9a6afe57f901366a1b8281865a91f1fe0853e4b50cc8b070";
+
+    fn key(hex: &str) -> [u8; 32] {
+        assert_eq!(hex.len(), 64);
+        let mut key = [0u8; 32];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+        }
+        key
+    }
+
+    fn signing_key_of(descriptor: &str) -> [u8; 32] {
+        let certificate = armored_object(descriptor, "descriptor-signing-key-cert").unwrap();
+        signing_key(&certificate).unwrap()
+    }
+
+    #[test]
+    fn blinded_key_comes_from_signing_key_certificate() {
+        assert_eq!(signing_key_of(DESCRIPTOR), key(BLINDED_KEY));
+        assert_eq!(
+            signing_key_of(CLIENT_AUTH_DESCRIPTOR),
+            key(CLIENT_AUTH_BLINDED_KEY)
+        );
+    }
+
+    #[test]
+    fn subcredential_matches_known_answer() {
+        assert_eq!(
+            subcredential(&key(CLIENT_AUTH_IDENTITY), &key(CLIENT_AUTH_BLINDED_KEY)),
+            key(CLIENT_AUTH_SUBCREDENTIAL)
+        );
+    }
+
+    #[test]
+    fn decrypts_introduction_points() {
+        let count = decrypt_introduction_points(DESCRIPTOR, &key(BLINDED_KEY), &key(SUBCREDENTIAL));
+        assert_eq!(count, Ok(3));
+    }
+
+    #[test]
+    fn tampered_layer_fails_mac_check() {
+        let tampered = DESCRIPTOR.replacen("srvrL84j", "trvrL84j", 1);
+        assert_ne!(tampered, DESCRIPTOR);
+        let count = decrypt_introduction_points(&tampered, &key(BLINDED_KEY), &key(SUBCREDENTIAL));
+        assert_eq!(count, Err(DescriptorError::MacMismatch("outer")));
+    }
+
+    #[test]
+    fn wrong_subcredential_fails_mac_check() {
+        let count = decrypt_introduction_points(
+            DESCRIPTOR,
+            &key(BLINDED_KEY),
+            &key(CLIENT_AUTH_SUBCREDENTIAL),
+        );
+        assert_eq!(count, Err(DescriptorError::MacMismatch("outer")));
+    }
+
+    #[test]
+    fn client_auth_descriptor_needs_client_key_for_inner_layer() {
+        let onion = OnionAddress::from_public_key(key(CLIENT_AUTH_IDENTITY));
+        assert_eq!(
+            count_introduction_points(CLIENT_AUTH_DESCRIPTOR, &onion),
+            Err(DescriptorError::MacMismatch("inner"))
+        );
+    }
+
+    #[test]
+    fn missing_objects_are_reported() {
+        assert_eq!(
+            decrypt_introduction_points("hs-descriptor 3\n", &[0; 32], &[0; 32]),
+            Err(DescriptorError::Missing("revision-counter"))
+        );
+        assert_eq!(
+            armored_object("hs-descriptor 3\n", "superencrypted"),
+            Err(DescriptorError::Missing("superencrypted"))
+        );
+    }
+}
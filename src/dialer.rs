@@ -0,0 +1,416 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::sleep;
+
+use crate::connection::{
+    Socks5Credentials, Socks5Error, socks5_connect_authenticated, socks5_probe, socks5_resolve,
+};
+use crate::types::CheckTimings;
+
+#[derive(Error, Debug)]
+pub enum DialError {
+    #[error(transparent)]
+    Socks5(#[from] Socks5Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Proxy unavailable: {0}")]
+    ProxyUnavailable(String),
+    #[error("DNS resolution failed: {0}")]
+    Resolve(String),
+    #[error("Name resolution is not supported by this dialer")]
+    ResolveUnsupported,
+    #[error("{0}")]
+    Other(String),
+}
+
+pub type Result<T> = std::result::Result<T, DialError>;
+
+/// A bidirectional byte stream to a dialed endpoint
+pub trait DialStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> DialStream for T {}
+
+pub type BoxedStream = Box<dyn DialStream>;
+
+pub type DialFuture<'a> = Pin<Box<dyn Future<Output = Result<BoxedStream>> + Send + 'a>>;
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<IpAddr>> + Send + 'a>>;
+
+pub type ProbeFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Transport used by the monitor to open connections to endpoints
+///
+/// Implement this to plug in your own transport, for example an embedded Tor client.
+pub trait Dialer: Send + Sync {
+    /// Open a stream to `host:port`
+    ///
+    /// Implementations record the duration of each transport phase they go through in
+    /// `timings` as soon as the phase completes.
+    fn dial<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        timings: &'a mut CheckTimings,
+    ) -> DialFuture<'a>;
+
+    /// Resolve `host` to an address the same way [`Dialer::dial`] would reach it
+    ///
+    /// The default implementation returns [`DialError::ResolveUnsupported`], in which case
+    /// the monitor hands hostnames straight to `dial`.
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        let _ = host;
+        Box::pin(async { Err(DialError::ResolveUnsupported) })
+    }
+
+    /// Check that the transport itself (e.g. the local proxy) is usable
+    ///
+    /// The monitor probes every proxy dialer once per cycle and doesn't blame endpoints for
+    /// failures while the probe fails. The default implementation always succeeds.
+    fn probe(&self) -> ProbeFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Keep streams dialed from now on off the circuits used by earlier ones
+    ///
+    /// The monitor calls this with a new `token` at the start of each cycle when cycles are
+    /// isolated. The default implementation does nothing.
+    fn isolate(&self, token: &str) {
+        let _ = token;
+    }
+}
+
+/// Dials endpoints through a SOCKS5 proxy such as the local Tor client
+///
+/// Once [`Dialer::isolate`] was called, connections authenticate with the token as SOCKS
+/// username, which Tor uses to keep them on circuits of their own.
+#[derive(Debug, Clone)]
+pub struct Socks5Dialer {
+    proxy_addr: SocketAddr,
+    isolation: Arc<Mutex<Option<Socks5Credentials>>>,
+}
+
+impl Socks5Dialer {
+    pub fn new(proxy_addr: SocketAddr) -> Self {
+        Self {
+            proxy_addr,
+            isolation: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Dialer for Socks5Dialer {
+    fn dial<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        timings: &'a mut CheckTimings,
+    ) -> DialFuture<'a> {
+        let credentials = self.isolation.lock().unwrap().clone();
+
+        Box::pin(async move {
+            match socks5_connect_authenticated(
+                self.proxy_addr,
+                host,
+                port,
+                credentials.as_ref(),
+                timings,
+            )
+            .await
+            {
+                Ok(stream) => Ok(Box::new(stream) as BoxedStream),
+                // Failing before the greeting completed means the proxy itself is the problem
+                Err(error) if timings.auth_ms.is_none() => Err(DialError::ProxyUnavailable(
+                    format!("{} ({})", self.proxy_addr, error),
+                )),
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            socks5_resolve(self.proxy_addr, host)
+                .await
+                .map_err(|error| match error {
+                    Socks5Error::ResolveFailed(reason) => DialError::Resolve(reason),
+                    Socks5Error::Io(error) => {
+                        DialError::ProxyUnavailable(format!("{} ({})", self.proxy_addr, error))
+                    }
+                    other => DialError::Socks5(other),
+                })
+        })
+    }
+
+    fn probe(&self) -> ProbeFuture<'_> {
+        Box::pin(async move {
+            socks5_probe(self.proxy_addr).await.map_err(|error| {
+                DialError::ProxyUnavailable(format!("{} ({})", self.proxy_addr, error))
+            })
+        })
+    }
+
+    fn isolate(&self, token: &str) {
+        *self.isolation.lock().unwrap() = Some(Socks5Credentials {
+            username: token.to_string(),
+            password: "isolate".to_string(),
+        });
+    }
+}
+
+/// Dials endpoints with a plain TCP connection
+#[derive(Debug, Clone, Default)]
+pub struct DirectDialer;
+
+impl Dialer for DirectDialer {
+    fn dial<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        timings: &'a mut CheckTimings,
+    ) -> DialFuture<'a> {
+        Box::pin(async move {
+            let phase_start = Instant::now();
+            let stream = TcpStream::connect((host, port)).await?;
+            timings.tcp_connect_ms = Some(phase_start.elapsed().as_millis() as u64);
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            lookup_host((host, 0))
+                .await
+                .map_err(|error| DialError::Resolve(error.to_string()))?
+                .next()
+                .map(|addr| addr.ip())
+                .ok_or_else(|| DialError::Resolve(format!("no addresses found for {}", host)))
+        })
+    }
+}
+
+/// Tries a list of dialers in order, moving on to the next one when a proxy is unavailable
+///
+/// Only [`DialError::ProxyUnavailable`] triggers failover; any other error comes from the
+/// target and would look the same through another proxy, so it is returned as is.
+pub struct FailoverDialer {
+    dialers: Vec<(String, Arc<dyn Dialer>)>,
+}
+
+impl FailoverDialer {
+    /// Create a failover dialer from `(name, dialer)` pairs in order of preference
+    pub fn new(dialers: Vec<(String, Arc<dyn Dialer>)>) -> Self {
+        Self { dialers }
+    }
+}
+
+impl Dialer for FailoverDialer {
+    fn dial<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        timings: &'a mut CheckTimings,
+    ) -> DialFuture<'a> {
+        Box::pin(async move {
+            let mut unavailable = Vec::new();
+            for (name, dialer) in &self.dialers {
+                *timings = CheckTimings::default();
+                match dialer.dial(host, port, timings).await {
+                    Err(DialError::ProxyUnavailable(reason)) => {
+                        println!("⚠️  Proxy {} unavailable, trying next: {}", name, reason);
+                        unavailable.push(name.as_str());
+                    }
+                    result => return result,
+                }
+            }
+            Err(DialError::ProxyUnavailable(format!(
+                "all proxies unavailable ({})",
+                unavailable.join(", ")
+            )))
+        })
+    }
+
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let mut unavailable = Vec::new();
+            for (name, dialer) in &self.dialers {
+                match dialer.resolve(host).await {
+                    Err(DialError::ProxyUnavailable(_)) => unavailable.push(name.as_str()),
+                    result => return result,
+                }
+            }
+            Err(DialError::ProxyUnavailable(format!(
+                "all proxies unavailable ({})",
+                unavailable.join(", ")
+            )))
+        })
+    }
+
+    fn isolate(&self, token: &str) {
+        for (_, dialer) in &self.dialers {
+            dialer.isolate(token);
+        }
+    }
+}
+
+/// What a [`ScriptedDialer`] does when asked to dial a target
+#[derive(Debug, Clone)]
+pub enum ScriptedOutcome {
+    /// Succeed after `delay` with an in-memory stream that is closed by the other side
+    Connect { delay: Duration },
+    /// Succeed after `delay` with an in-memory stream on which the other side sends `reply`
+    Respond { delay: Duration, reply: Vec<u8> },
+    /// Fail after `delay` with the given error message
+    Fail { delay: Duration, error: String },
+    /// Fail immediately as if the proxy could not be reached
+    ProxyUnavailable { reason: String },
+    /// Never complete, leaving it to the caller's timeout
+    Hang,
+}
+
+/// In-memory dialer that plays back scripted outcomes per target
+///
+/// Outcomes queued with [`ScriptedDialer::push`] are consumed in order for each `host:port`;
+/// once a target's queue is empty the fallback outcome is used. Delays go through
+/// `tokio::time`, so tests can run deterministically with a paused clock.
+#[derive(Debug)]
+pub struct ScriptedDialer {
+    scripts: Mutex<HashMap<String, VecDeque<ScriptedOutcome>>>,
+    resolutions: Mutex<HashMap<String, std::result::Result<IpAddr, String>>>,
+    probe_failure: Mutex<Option<String>>,
+    fallback: ScriptedOutcome,
+    dialed: Mutex<Vec<String>>,
+    isolation_tokens: Mutex<Vec<String>>,
+}
+
+impl ScriptedDialer {
+    pub fn new(fallback: ScriptedOutcome) -> Self {
+        Self {
+            scripts: Mutex::new(HashMap::new()),
+            resolutions: Mutex::new(HashMap::new()),
+            probe_failure: Mutex::new(None),
+            fallback,
+            dialed: Mutex::new(Vec::new()),
+            isolation_tokens: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queue an outcome for the next dial of `host:port`
+    pub fn push(&self, host: &str, port: u16, outcome: ScriptedOutcome) {
+        self.scripts
+            .lock()
+            .unwrap()
+            .entry(format!("{}:{}", host, port))
+            .or_default()
+            .push_back(outcome);
+    }
+
+    /// Answer name resolution for `host` with an address or a failure reason
+    ///
+    /// Hosts without a scripted answer report [`DialError::ResolveUnsupported`].
+    pub fn set_resolution(&self, host: &str, answer: std::result::Result<IpAddr, String>) {
+        self.resolutions
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), answer);
+    }
+
+    /// Make probes fail with `reason` (or succeed again with `None`)
+    pub fn set_probe_failure(&self, reason: Option<String>) {
+        *self.probe_failure.lock().unwrap() = reason;
+    }
+
+    /// Targets dialed so far, in order
+    pub fn dialed(&self) -> Vec<String> {
+        self.dialed.lock().unwrap().clone()
+    }
+
+    /// Tokens passed to [`Dialer::isolate`] so far, in order
+    pub fn isolation_tokens(&self) -> Vec<String> {
+        self.isolation_tokens.lock().unwrap().clone()
+    }
+
+    fn next_outcome(&self, target: &str) -> ScriptedOutcome {
+        self.dialed.lock().unwrap().push(target.to_string());
+        self.scripts
+            .lock()
+            .unwrap()
+            .get_mut(target)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| self.fallback.clone())
+    }
+}
+
+impl Dialer for ScriptedDialer {
+    fn dial<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        timings: &'a mut CheckTimings,
+    ) -> DialFuture<'a> {
+        let outcome = self.next_outcome(&format!("{}:{}", host, port));
+
+        Box::pin(async move {
+            match outcome {
+                ScriptedOutcome::Connect { delay } => {
+                    sleep(delay).await;
+                    timings.tcp_connect_ms = Some(delay.as_millis() as u64);
+                    let (stream, _peer) = tokio::io::duplex(1024);
+                    Ok(Box::new(stream) as BoxedStream)
+                }
+                ScriptedOutcome::Respond { delay, reply } => {
+                    sleep(delay).await;
+                    timings.tcp_connect_ms = Some(delay.as_millis() as u64);
+                    let (stream, mut peer) = tokio::io::duplex(reply.len().max(1024));
+                    peer.write_all(&reply).await?;
+                    Ok(Box::new(stream) as BoxedStream)
+                }
+                ScriptedOutcome::Fail { delay, error } => {
+                    sleep(delay).await;
+                    Err(DialError::Other(error))
+                }
+                ScriptedOutcome::ProxyUnavailable { reason } => {
+                    Err(DialError::ProxyUnavailable(reason))
+                }
+                ScriptedOutcome::Hang => std::future::pending().await,
+            }
+        })
+    }
+
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        let answer = self.resolutions.lock().unwrap().get(host).cloned();
+
+        Box::pin(async move {
+            match answer {
+                Some(Ok(address)) => Ok(address),
+                Some(Err(reason)) => Err(DialError::Resolve(reason)),
+                None => Err(DialError::ResolveUnsupported),
+            }
+        })
+    }
+
+    fn probe(&self) -> ProbeFuture<'_> {
+        let failure = self.probe_failure.lock().unwrap().clone();
+
+        Box::pin(async move {
+            match failure {
+                Some(reason) => Err(DialError::ProxyUnavailable(reason)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn isolate(&self, token: &str) {
+        self.isolation_tokens
+            .lock()
+            .unwrap()
+            .push(token.to_string());
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::types::CheckTimings;
+
+/// JoinMarket onion messaging protocol version we speak
+const JM_PROTOCOL_VERSION: u64 = 5;
+
+/// Message type of a peer's handshake
+const HANDSHAKE_MESSAGE_TYPE: u64 = 793;
+
+/// Message type of a directory node's handshake reply
+const DN_HANDSHAKE_MESSAGE_TYPE: u64 = 795;
+
+/// Location string announced by peers that don't serve an onion of their own
+const NOT_SERVING_ONION: &str = "NOT-SERVING-ONION";
+
+/// Longest line a directory is allowed to send us
+const MAX_LINE_LENGTH: u64 = 40_000;
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection closed before handshake reply")]
+    Closed,
+    #[error("Invalid handshake reply: {0}")]
+    InvalidReply(String),
+    #[error("Unexpected message type {0}")]
+    UnexpectedMessageType(u64),
+    #[error("Handshake rejected by directory")]
+    Rejected,
+}
+
+pub type Result<T> = std::result::Result<T, HandshakeError>;
+
+#[derive(Debug, Deserialize)]
+struct OnionMessage {
+    #[serde(rename = "type")]
+    message_type: u64,
+    line: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DirectoryHandshake {
+    #[serde(rename = "app-name")]
+    app_name: String,
+    directory: bool,
+    accepted: bool,
+}
+
+/// Perform the JoinMarket onion messaging handshake with a directory node
+///
+/// Sends a non-serving peer handshake and waits for the directory's `dn-handshake` reply,
+/// recording the time to the first reply byte and to the complete reply in `timings`.
+pub async fn joinmarket_handshake<S>(stream: &mut S, timings: &mut CheckTimings) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = json!({
+        "app-name": "joinmarket",
+        "directory": false,
+        "location-string": NOT_SERVING_ONION,
+        "proto-ver": JM_PROTOCOL_VERSION,
+        "features": {},
+        "nick": monitor_nick(),
+    });
+    let message = json!({ "type": HANDSHAKE_MESSAGE_TYPE, "line": hello.to_string() });
+
+    let phase_start = Instant::now();
+    stream
+        .write_all(format!("{}\r\n", message).as_bytes())
+        .await?;
+    stream.flush().await?;
+
+    let mut reader = BufReader::new(stream);
+    if reader.fill_buf().await?.is_empty() {
+        return Err(HandshakeError::Closed);
+    }
+    timings.first_byte_ms = Some(phase_start.elapsed().as_millis() as u64);
+
+    let mut line = Vec::new();
+    (&mut reader)
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if !line.ends_with(b"\n") {
+        return Err(HandshakeError::Closed);
+    }
+
+    let reply: OnionMessage = serde_json::from_slice(&line)
+        .map_err(|error| HandshakeError::InvalidReply(error.to_string()))?;
+    if reply.message_type != DN_HANDSHAKE_MESSAGE_TYPE {
+        return Err(HandshakeError::UnexpectedMessageType(reply.message_type));
+    }
+
+    let handshake: DirectoryHandshake = serde_json::from_str(&reply.line)
+        .map_err(|error| HandshakeError::InvalidReply(error.to_string()))?;
+    if handshake.app_name != "joinmarket" || !handshake.directory {
+        return Err(HandshakeError::InvalidReply(
+            "peer is not a JoinMarket directory".to_string(),
+        ));
+    }
+    if !handshake.accepted {
+        return Err(HandshakeError::Rejected);
+    }
+    timings.handshake_ms = Some(phase_start.elapsed().as_millis() as u64);
+
+    Ok(())
+}
+
+/// Throwaway nick in the JoinMarket format so the directory doesn't mistake us for a known peer
+fn monitor_nick() -> String {
+    const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+        | 1;
+    let mut nick = format!("J{}", JM_PROTOCOL_VERSION);
+    for _ in 0..14 {
+        // xorshift is plenty for picking a nick
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        nick.push(ALPHABET[(seed % ALPHABET.len() as u64) as usize] as char);
+    }
+    nick
+}
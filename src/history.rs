@@ -0,0 +1,371 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, interval_at};
+
+use crate::monitor::SharedStatusStore;
+use crate::types::{HistogramBucket, LatencyStats, Sample, Uptime};
+
+/// How long check results are kept, which is also the longest uptime window
+pub const RETENTION: TimeDelta = TimeDelta::days(30);
+
+/// Upper bound on the results kept per endpoint, whatever its check interval
+pub const MAX_SAMPLES: usize = 100_000;
+
+/// How often results older than [`RETENTION`] are dropped from the history file
+const COMPACT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Upper limits of the response time histogram's buckets, spread for Tor's slow circuits
+const HISTOGRAM_BOUNDS_MS: [u64; 9] = [100, 250, 500, 1000, 2000, 5000, 10000, 20000, 30000];
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("Failed to access history file {path}: {source}")]
+    File {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+/// One line of the history file
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    endpoint: String,
+    #[serde(flatten)]
+    sample: Sample,
+}
+
+/// What [`HistoryLog`] sends to the writer
+enum Message {
+    Line(String),
+    /// Reply once every line sent before is on disk
+    Flush(oneshot::Sender<()>),
+}
+
+/// Appends check results to the history file
+#[derive(Clone)]
+pub struct HistoryLog {
+    sender: UnboundedSender<Message>,
+}
+
+/// Restores the history file into the status store and then writes what [`HistoryLog`] sends
+pub struct HistoryWriter {
+    path: PathBuf,
+    receiver: UnboundedReceiver<Message>,
+}
+
+/// Create the two ends of a history file; results logged before the writer runs are queued
+pub fn history_file(path: PathBuf) -> (HistoryLog, HistoryWriter) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (HistoryLog { sender }, HistoryWriter { path, receiver })
+}
+
+impl HistoryLog {
+    pub fn record(&self, key: &str, sample: &Sample) {
+        let entry = Entry {
+            endpoint: key.to_string(),
+            sample: sample.clone(),
+        };
+        if let Ok(line) = serde_json::to_string(&entry) {
+            // Nothing is listening if the file couldn't be restored, which was reported then
+            let _ = self.sender.send(Message::Line(line));
+        }
+    }
+
+    /// Wait until every result recorded so far is written to disk, dropping old results from
+    /// the file on the way
+    ///
+    /// Returns right away when history isn't being written. Must not be called before
+    /// [`HistoryWriter::start`], as nothing would write the queued results yet.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+impl HistoryWriter {
+    /// Load the results kept from earlier runs and start appending new ones
+    ///
+    /// Results older than [`RETENTION`] are dropped from the file on the way, and again once a
+    /// day and on every flush. Results of endpoints that are no longer configured stay in the
+    /// file, in case they come back.
+    pub async fn start(self, status_store: &SharedStatusStore) -> Result<(), HistoryError> {
+        let now = Utc::now();
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(source) => return Err(file_error(&self.path, source)),
+        };
+
+        let mut restored = 0;
+        let (kept, invalid) = {
+            let mut store = status_store.write().await;
+            prune(&contents, now, |entry| {
+                if let Some(endpoint_info) = store.endpoints.get_mut(&entry.endpoint) {
+                    push_sample(&mut endpoint_info.samples, entry.sample);
+                    restored += 1;
+                }
+            })
+        };
+        if invalid > 0 {
+            println!(
+                "⚠️  Skipped {} unreadable lines in {}",
+                invalid,
+                self.path.display()
+            );
+        }
+        println!(
+            "📈 Restored {} check results from {}",
+            restored,
+            self.path.display()
+        );
+
+        let mut file = replace(&self.path, kept).await?;
+        let HistoryWriter { path, mut receiver } = self;
+        tokio::spawn(async move {
+            let mut compact_timer =
+                interval_at(Instant::now() + COMPACT_INTERVAL, COMPACT_INTERVAL);
+            loop {
+                tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(Message::Line(mut line)) => {
+                            line.push('\n');
+                            if let Err(error) = file.write_all(line.as_bytes()).await {
+                                println!("⚠️  Failed to write to {}: {}", path.display(), error);
+                            }
+                        }
+                        Some(Message::Flush(done)) => {
+                            compact(&path, &mut file, Utc::now()).await;
+                            if let Err(error) = file.sync_data().await {
+                                println!("⚠️  Failed to write to {}: {}", path.display(), error);
+                            }
+                            let _ = done.send(());
+                        }
+                        None => break,
+                    },
+                    _ = compact_timer.tick() => compact(&path, &mut file, Utc::now()).await,
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The lines of `contents` with results within [`RETENTION`] of `now`, and how many lines
+/// couldn't be read; `keep` sees each result that is kept
+fn prune(contents: &str, now: DateTime<Utc>, mut keep: impl FnMut(Entry)) -> (String, usize) {
+    let mut kept = String::new();
+    let mut invalid = 0;
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(entry) = serde_json::from_str::<Entry>(line) else {
+            invalid += 1;
+            continue;
+        };
+        if now - entry.sample.at > RETENTION {
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+        keep(entry);
+    }
+    (kept, invalid)
+}
+
+/// Swap the history file for one holding `contents` and open it for appending
+async fn replace(path: &Path, contents: String) -> Result<tokio::fs::File, HistoryError> {
+    // Write the pruned history next to the old one first, so a crash can't lose it
+    let temporary = PathBuf::from(format!("{}.tmp", path.display()));
+    tokio::fs::write(&temporary, contents)
+        .await
+        .map_err(|source| file_error(&temporary, source))?;
+    tokio::fs::rename(&temporary, path)
+        .await
+        .map_err(|source| file_error(path, source))?;
+    tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|source| file_error(path, source))
+}
+
+/// Drop results older than [`RETENTION`] at `now` from the history file that `file` appends to
+///
+/// On failure the file is left as it is and `file` keeps appending to it.
+async fn compact(path: &Path, file: &mut tokio::fs::File, now: DateTime<Utc>) {
+    let compacted = async {
+        file.flush()
+            .await
+            .map_err(|source| file_error(path, source))?;
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|source| file_error(path, source))?;
+        let (kept, _) = prune(&contents, now, |_| {});
+        replace(path, kept).await
+    };
+    match compacted.await {
+        Ok(compacted) => *file = compacted,
+        Err(error) => println!("⚠️  Failed to compact history: {}", error),
+    }
+}
+
+/// Add a result, dropping those that are too old or too many
+pub fn push_sample(samples: &mut VecDeque<Sample>, sample: Sample) {
+    let cutoff = sample.at - RETENTION;
+    while samples
+        .front()
+        .is_some_and(|oldest| oldest.at < cutoff || samples.len() >= MAX_SAMPLES)
+    {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Share of results within the last 24 hours, 7 days and 30 days that found the endpoint up
+pub fn uptime(samples: &VecDeque<Sample>, now: DateTime<Utc>) -> Uptime {
+    Uptime {
+        day: uptime_since(samples, now - TimeDelta::days(1)),
+        week: uptime_since(samples, now - TimeDelta::days(7)),
+        month: uptime_since(samples, now - RETENTION),
+    }
+}
+
+/// Percentage of results since `start` that found the endpoint up, `None` without results
+fn uptime_since(samples: &VecDeque<Sample>, start: DateTime<Utc>) -> Option<f64> {
+    let (up, total) = samples
+        .iter()
+        .filter(|sample| sample.at >= start)
+        .fold((0usize, 0usize), |(up, total), sample| {
+            (up + usize::from(sample.up), total + 1)
+        });
+    (total > 0).then(|| up as f64 * 100.0 / total as f64)
+}
+
+/// Percentiles and histogram of the response times since `now - window`, `None` without any
+pub fn latency_stats(
+    samples: &VecDeque<Sample>,
+    now: DateTime<Utc>,
+    window: TimeDelta,
+) -> Option<LatencyStats> {
+    let start = now - window;
+    let mut response_times: Vec<u64> = samples
+        .iter()
+        .filter(|sample| sample.at >= start)
+        .filter_map(|sample| sample.response_time_ms)
+        .collect();
+    response_times.sort_unstable();
+    let (&min_ms, &max_ms) = (response_times.first()?, response_times.last()?);
+
+    // Nearest rank, so every percentile is a response time that was actually measured
+    let percentile = |percent: usize| {
+        let rank = (response_times.len() * percent).div_ceil(100).max(1);
+        response_times[rank - 1]
+    };
+
+    let mut histogram: Vec<HistogramBucket> = HISTOGRAM_BOUNDS_MS
+        .iter()
+        .map(|&upper_ms| Some(upper_ms))
+        .chain([None])
+        .map(|upper_ms| HistogramBucket { upper_ms, count: 0 })
+        .collect();
+    for &response_time in &response_times {
+        let bucket = HISTOGRAM_BOUNDS_MS
+            .iter()
+            .position(|&upper_ms| response_time <= upper_ms)
+            .unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+        histogram[bucket].count += 1;
+    }
+
+    Some(LatencyStats {
+        window_seconds: window.num_seconds().max(0) as u64,
+        count: response_times.len(),
+        min_ms,
+        p50_ms: percentile(50),
+        p90_ms: percentile(90),
+        p99_ms: percentile(99),
+        max_ms,
+        histogram,
+    })
+}
+
+fn file_error(path: &Path, source: std::io::Error) -> HistoryError {
+    HistoryError::File {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(endpoint: &str, at: DateTime<Utc>) -> String {
+        serde_json::to_string(&Entry {
+            endpoint: endpoint.to_string(),
+            sample: Sample {
+                at,
+                up: true,
+                response_time_ms: Some(100),
+            },
+        })
+        .unwrap()
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-06-01T12:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn prune_keeps_results_within_retention() {
+        let expired = line("a:1", now() - RETENTION - TimeDelta::seconds(1));
+        let oldest = line("a:1", now() - RETENTION);
+        let recent = line("b:2", now() - TimeDelta::days(1));
+        let contents = format!("{expired}\nnot json\n\n{oldest}\n{recent}\n");
+
+        let mut seen = Vec::new();
+        let (kept, invalid) = prune(&contents, now(), |entry| seen.push(entry.endpoint));
+        assert_eq!(kept, format!("{oldest}\n{recent}\n"));
+        assert_eq!(invalid, 1);
+        assert_eq!(seen, vec!["a:1", "b:2"]);
+    }
+
+    #[tokio::test]
+    async fn compact_rewrites_the_file_and_keeps_appending() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", std::process::id()));
+        let expired = line("a:1", now() - RETENTION - TimeDelta::days(1));
+        let recent = line("a:1", now() - TimeDelta::days(1));
+        tokio::fs::write(&path, format!("{expired}\n"))
+            .await
+            .unwrap();
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        // Still buffered when compaction starts
+        file.write_all(format!("{recent}\n").as_bytes())
+            .await
+            .unwrap();
+
+        compact(&path, &mut file, now()).await;
+        let latest = line("a:1", now());
+        file.write_all(format!("{latest}\n").as_bytes())
+            .await
+            .unwrap();
+        file.flush().await.unwrap();
+
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(written, format!("{recent}\n{latest}\n"));
+    }
+}
@@ -1,6 +1,14 @@
+pub mod circuits;
 pub mod config;
 pub mod connection;
+pub mod descriptor;
+pub mod dialer;
+pub mod handshake;
+pub mod history;
 pub mod monitor;
+pub mod onion;
+pub mod onion_service;
 pub mod server;
 pub mod templates;
+pub mod tor_control;
 pub mod types;
@@ -7,7 +7,13 @@ use joinmarket_directory_checker::server::run_server;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Load configuration
     let config = if Path::new("config.toml").exists() {
-        load_config("config.toml")?
+        match load_config("config.toml") {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("❌ Failed to load config.toml: {}", error);
+                std::process::exit(1);
+            }
+        }
     } else {
         println!("⚠️  config.toml not found, using default configuration");
         create_default_config()
@@ -16,7 +22,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("💡 Create config.toml to customize endpoints and settings");
     println!("🔧 Configuration loaded:");
     println!("   Server: {}:{}", config.server.host, config.server.port);
-    println!("   Proxy: {}:{}", config.proxy.host, config.proxy.port);
+    for proxy in config.all_proxies() {
+        println!(
+            "   Proxy: {} ({}:{})",
+            proxy.label(),
+            proxy.host,
+            proxy.port
+        );
+    }
     println!("   Endpoints: {}", config.endpoints.len());
     println!(
         "   Check interval: {}s",
@@ -1,48 +1,479 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use chrono::Utc;
-use tokio::sync::RwLock;
-use tokio::time::sleep;
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::{RwLock, Semaphore, broadcast, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
 
-use crate::connection::socks5_connect;
-use crate::types::{Config, Endpoint, EndpointInfo, EndpointStatus, StatusStore};
+use crate::circuits::CircuitTracker;
+use crate::connection::Socks5Error;
+use crate::dialer::{DialError, Dialer, DirectDialer, FailoverDialer, Socks5Dialer};
+use crate::handshake::{HandshakeError, joinmarket_handshake};
+use crate::history::{HistoryLog, HistoryWriter, history_file, latency_stats, push_sample};
+use crate::tor_control::{fetch_descriptor, fetch_tor_status, register_client_auth, signal_newnym};
+use crate::types::{
+    CheckMode, CheckRecord, CheckTimings, Config, Endpoint, EndpointInfo, EndpointStatus,
+    FailureKind, FreshIdentity, LatencyAnomaly, LatencyBaseline, ProxyHealth, ProxyMode,
+    ProxyStatus, ReferenceStatus, Sample, StatusStore, TorControlConfig, TorStatus, Transition,
+    VantageResult,
+};
 
 pub type SharedStatusStore = Arc<RwLock<StatusStore>>;
 
+/// How long to wait for an HSDir to answer a descriptor fetch
+const DESCRIPTOR_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of checks kept in each endpoint's history
+const HISTORY_LENGTH: usize = 100;
+
+/// Number of status changes kept for each endpoint
+const TRANSITION_HISTORY: usize = 100;
+
+/// Events kept for subscribers that fall behind
+const EVENT_CAPACITY: usize = 64;
+
+/// Period `max_checks_per_minute` applies to
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+/// Checks starting later than this after they were due count as late
+const LATE_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// How much the interval of an endpoint that keeps working grows with each check, in adaptive
+/// mode; intervals of endpoints having trouble are halved
+const INTERVAL_GROWTH: f64 = 1.5;
+
+/// Time for Tor's `STREAM` and `CIRC` events about a probe to arrive after it finished
+const CIRCUIT_EVENT_GRACE: Duration = Duration::from_millis(250);
+
+/// Longest wait between retries, however far the backoff has doubled
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Tor ignores `SIGNAL NEWNYM` more often than this
+const NEWNYM_RATE_LIMIT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum CheckError {
+    #[error(transparent)]
+    Dial(#[from] DialError),
+    #[error("Handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+}
+
+impl CheckError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            CheckError::Dial(DialError::ProxyUnavailable(_)) => FailureKind::Proxy,
+            CheckError::Dial(DialError::Resolve(_)) => FailureKind::Resolve,
+            CheckError::Dial(DialError::Socks5(Socks5Error::ClientAuthorization(_))) => {
+                FailureKind::ClientAuth
+            }
+            CheckError::Dial(_) => FailureKind::Connect,
+            CheckError::Handshake(_) => FailureKind::Handshake,
+        }
+    }
+}
+
+/// Something subscribers may want to be notified about
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MonitorEvent {
+    /// A check took much longer than the endpoint's learned baseline
+    SlowerThanUsual {
+        endpoint: String,
+        name: String,
+        response_time_ms: u64,
+        baseline_ms: u64,
+    },
+}
+
+/// A named way of reaching endpoints, e.g. one particular proxy
+///
+/// Instead of a dialer it holds the reason when the proxy was found to be down at the start of
+/// the cycle.
+type Vantage = (String, Result<Arc<dyn Dialer>, String>);
+
+/// A proxy dialer and the name it is shown under
+type NamedDialer = (String, Arc<dyn Dialer>);
+
+/// The dialers checks can use, along with which proxies were up at the start of the latest cycle
+#[derive(Clone)]
+struct Dialers {
+    proxy_mode: ProxyMode,
+    proxies: Vec<NamedDialer>,
+    direct: Arc<dyn Dialer>,
+    available: Arc<Mutex<Vec<NamedDialer>>>,
+}
+
+impl Dialers {
+    /// The ways an endpoint should be checked right now
+    fn vantages_for(&self, endpoint: &Endpoint) -> Vec<Vantage> {
+        let available_proxies = self.available.lock().unwrap().clone();
+        match (endpoint.mode, self.proxy_mode) {
+            (CheckMode::Direct, _) => {
+                vec![("direct".to_string(), Ok(self.direct.clone()))]
+            }
+            (CheckMode::Proxy, ProxyMode::Compare) if self.proxies.len() > 1 => self
+                .proxies
+                .iter()
+                .map(|(name, dialer)| {
+                    let available = available_proxies
+                        .iter()
+                        .any(|(available_name, _)| available_name == name);
+                    let dialer = if available {
+                        Ok(dialer.clone())
+                    } else {
+                        Err(format!("proxy {} is down", name))
+                    };
+                    (name.clone(), dialer)
+                })
+                .collect(),
+            (CheckMode::Proxy, _) => {
+                vec![("proxy".to_string(), failover_dialer(&available_proxies))]
+            }
+        }
+    }
+}
+
+/// A check handed to the runtime, which may still be waiting for a concurrency permit
+struct SpawnedCheck {
+    task: JoinHandle<()>,
+    /// Set once the check has its permit and is running
+    started: Arc<AtomicBool>,
+}
+
+impl SpawnedCheck {
+    fn is_queued(&self) -> bool {
+        !self.task.is_finished() && !self.started.load(Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        !self.task.is_finished() && self.started.load(Ordering::Relaxed)
+    }
+}
+
+/// What a check needs besides the endpoint and the ways of reaching it
+#[derive(Clone)]
+struct CheckContext {
+    connection_timeout: Duration,
+    /// Extra attempts after a failed one, the first after `retry_backoff`, doubling from there
+    retries: u32,
+    retry_backoff: Duration,
+    failures_before_offline: u32,
+    successes_before_online: u32,
+    degraded_response_ms: Option<u64>,
+    flapping_changes: Option<usize>,
+    flapping_window: Duration,
+    latency_window: Duration,
+    anomaly_deviations: f64,
+    status_store: SharedStatusStore,
+    events: broadcast::Sender<MonitorEvent>,
+    /// Where results are persisted, when configured
+    history_log: Option<HistoryLog>,
+    /// Control port to fetch descriptors through when an onion service fails
+    descriptor_fetch: Option<TorControlConfig>,
+    /// Endpoints whose descriptor is being fetched, so fetches of a failing service don't pile up
+    descriptor_fetches: Arc<Mutex<HashSet<String>>>,
+    circuit_tracker: Option<CircuitTracker>,
+}
+
+/// When an endpoint is due and whether its last check is still running
+struct ScheduledEndpoint {
+    endpoint: Endpoint,
+    key: String,
+    /// The endpoint's configured interval
+    base_interval: Duration,
+    /// The interval in use, which moves away from `base_interval` in adaptive mode
+    interval: Duration,
+    /// Start of the interval the next check belongs to, moved on by the interval however long
+    /// checks take, so the schedule doesn't drift
+    slot: Instant,
+    /// When the next check should start, i.e. its slot moved by jitter
+    due: Instant,
+    /// When the scheduler looks at the endpoint again, after `due` while the budget is used up
+    next_check: Instant,
+    /// The latest check, which may still be queued or running
+    check: Option<SpawnedCheck>,
+}
+
+impl ScheduledEndpoint {
+    /// Move on to the first slot after `now`, returning how many slots were missed on the way
+    fn advance(&mut self, now: Instant, jitter_percent: u8) -> u64 {
+        let mut missed = 0;
+        let mut previous = self.slot;
+        self.slot += self.interval;
+        while self.slot <= now {
+            previous = self.slot;
+            self.slot += self.interval;
+            missed += 1;
+        }
+        self.due = previous + jittered(self.interval, jitter_percent);
+        self.next_check = self.due;
+        missed
+    }
+}
+
+/// The endpoint checks that became due within one interval of the global schedule
+///
+/// A round lasts from the start of its interval until the last of its checks finished, and
+/// overran if that took longer than the interval.
+struct Round {
+    start: Instant,
+    interval: Duration,
+    state: Mutex<RoundState>,
+}
+
+#[derive(Default)]
+struct RoundState {
+    checks: usize,
+    /// Checks of the round that haven't finished yet
+    pending: usize,
+    /// Set once the interval is over, so no further checks join the round
+    closed: bool,
+    last_finished: Option<Instant>,
+}
+
+impl Round {
+    fn new(start: Instant, interval: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            start,
+            interval,
+            state: Mutex::new(RoundState::default()),
+        })
+    }
+
+    fn join(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.checks += 1;
+        state.pending += 1;
+    }
+
+    /// Count one of the round's checks as finished, returning the round's duration if it was
+    /// the last one
+    fn finish(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.pending -= 1;
+        state.last_finished = Some(Instant::now());
+        self.duration(&state)
+    }
+
+    /// End the interval, returning the round's duration if its checks are already done
+    fn close(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.duration(&state)
+    }
+
+    /// A round without checks has nothing to measure
+    fn duration(&self, state: &RoundState) -> Option<Duration> {
+        if !state.closed || state.pending > 0 {
+            return None;
+        }
+        let last_finished = state.last_finished?;
+        Some(last_finished.saturating_duration_since(self.start))
+    }
+}
+
+/// Limits how many checks start within a minute
+struct CheckBudget {
+    limit: usize,
+    started: VecDeque<Instant>,
+}
+
+impl CheckBudget {
+    /// Count a check starting now, or tell when the next one may start
+    fn take(&mut self, now: Instant) -> Result<(), Instant> {
+        while self
+            .started
+            .front()
+            .is_some_and(|&started| now.duration_since(started) >= BUDGET_WINDOW)
+        {
+            self.started.pop_front();
+        }
+        match self.started.front() {
+            Some(&oldest) if self.started.len() >= self.limit => Err(oldest + BUDGET_WINDOW),
+            _ => {
+                self.started.push_back(now);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A monitor running in the background, see [`MonitorService::start`]
+pub struct MonitorHandle {
+    status_store: SharedStatusStore,
+    shutdown: Arc<watch::Sender<bool>>,
+    task: JoinHandle<()>,
+}
+
+impl MonitorHandle {
+    pub fn status_store(&self) -> SharedStatusStore {
+        self.status_store.clone()
+    }
+
+    /// Stop scheduling checks and wait until running checks are done or cancelled and history
+    /// is written
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.task.await;
+    }
+
+    /// Wrap `signal` so monitoring starts winding down as soon as it fires, without waiting
+    /// for it, e.g. to hand to the web server's graceful shutdown
+    pub fn stop_on(
+        &self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let shutdown = self.shutdown.clone();
+        async move {
+            signal.await;
+            let _ = shutdown.send(true);
+        }
+    }
+}
+
 pub struct MonitorService {
     config: Config,
     status_store: SharedStatusStore,
+    dialers: Dialers,
+    circuit_tracker: CircuitTracker,
+    last_newnym: Mutex<Option<Instant>>,
+    /// Limits how many checks run at once, when configured
+    check_permits: Option<Arc<Semaphore>>,
+    history_log: Option<HistoryLog>,
+    /// Taken when monitoring starts, to restore earlier results before appending new ones
+    history_writer: Mutex<Option<HistoryWriter>>,
+    events: broadcast::Sender<MonitorEvent>,
+    descriptor_fetches: Arc<Mutex<HashSet<String>>>,
 }
 
 impl MonitorService {
     pub fn new(config: Config) -> Self {
-        let status_store = Arc::new(RwLock::new(HashMap::new()));
+        let proxy_dialers = config
+            .all_proxies()
+            .into_iter()
+            .filter_map(|proxy| match proxy.socket_addr() {
+                Ok(proxy_addr) => Some((
+                    proxy.label(),
+                    Arc::new(Socks5Dialer::new(proxy_addr)) as Arc<dyn Dialer>,
+                )),
+                Err(error) => {
+                    println!("⚠️  Ignoring proxy {}: {}", proxy.label(), error);
+                    None
+                }
+            })
+            .collect();
 
-        // Initialize status store with all endpoints
-        let mut initial_store = HashMap::new();
+        Self::with_proxy_dialers(config, proxy_dialers, Arc::new(DirectDialer))
+    }
+
+    /// Create a monitor that reaches endpoints through the given dialers
+    ///
+    /// `proxy_dialer` is used for endpoints in [`CheckMode::Proxy`] and `direct_dialer` for
+    /// endpoints in [`CheckMode::Direct`].
+    pub fn with_dialers(
+        config: Config,
+        proxy_dialer: Arc<dyn Dialer>,
+        direct_dialer: Arc<dyn Dialer>,
+    ) -> Self {
+        Self::with_proxy_dialers(
+            config,
+            vec![("proxy".to_string(), proxy_dialer)],
+            direct_dialer,
+        )
+    }
+
+    /// Create a monitor with several named proxy dialers
+    ///
+    /// The proxy dialers are used in order for failover, or all at once in
+    /// [`ProxyMode::Compare`].
+    pub fn with_proxy_dialers(
+        config: Config,
+        proxy_dialers: Vec<(String, Arc<dyn Dialer>)>,
+        direct_dialer: Arc<dyn Dialer>,
+    ) -> Self {
+        // Initialize status store with all endpoints and proxies
+        let mut initial_store = StatusStore::default();
         for endpoint in &config.endpoints {
             let key = format!("{}:{}", endpoint.address, endpoint.port);
             let endpoint_info = EndpointInfo {
                 endpoint: endpoint.clone(),
                 status: EndpointStatus::Unknown,
+                checking: false,
                 last_check: None,
+                last_timings: None,
+                vantages: Vec::new(),
+                descriptor: None,
+                history: VecDeque::new(),
+                consecutive_failures: 0,
+                consecutive_successes: 0,
+                confirmed_offline: false,
+                transitions: VecDeque::new(),
+                since: None,
+                samples: VecDeque::new(),
+                latency: None,
+                baseline: None,
+                anomaly: None,
+                relative_latency: None,
+                local_problem: false,
+                adapted_interval: None,
             };
-            initial_store.insert(key, endpoint_info);
+            initial_store.endpoints.insert(key, endpoint_info);
+        }
+        for reference in &config.references {
+            initial_store.references.push(ReferenceStatus {
+                name: reference.name.clone(),
+                address: reference.address.clone(),
+                port: reference.port,
+                status: EndpointStatus::Unknown,
+                last_check: None,
+            });
+        }
+        for (name, _) in &proxy_dialers {
+            initial_store.proxies.push(ProxyStatus {
+                name: name.clone(),
+                health: ProxyHealth::Unknown,
+                last_check: None,
+            });
         }
 
-        // Set the initial store
-        let store_clone = status_store.clone();
-        tokio::spawn(async move {
-            let mut store = store_clone.write().await;
-            *store = initial_store;
-        });
+        let check_permits = config
+            .monitoring
+            .max_concurrent_checks
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let (history_log, history_writer) = match config.monitoring.history_file.clone() {
+            Some(path) => {
+                let (history_log, history_writer) = history_file(path);
+                (Some(history_log), Some(history_writer))
+            }
+            None => (None, None),
+        };
+        let dialers = Dialers {
+            proxy_mode: config.monitoring.proxy_mode,
+            proxies: proxy_dialers,
+            direct: direct_dialer,
+            available: Arc::default(),
+        };
 
         Self {
             config,
-            status_store,
+            status_store: Arc::new(RwLock::new(initial_store)),
+            dialers,
+            circuit_tracker: CircuitTracker::default(),
+            last_newnym: Mutex::new(None),
+            check_permits,
+            history_log,
+            history_writer: Mutex::new(history_writer),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            descriptor_fetches: Arc::default(),
         }
     }
 
@@ -50,14 +481,17 @@ impl MonitorService {
         self.status_store.clone()
     }
 
-    pub async fn start_monitoring(&self) {
-        let proxy_addr = format!("{}:{}", self.config.proxy.host, self.config.proxy.port)
-            .parse::<SocketAddr>()
-            .expect("Invalid proxy address");
+    /// Receive [`MonitorEvent`]s from now on, e.g. to send notifications
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitorEvent> {
+        self.events.subscribe()
+    }
 
+    /// Check endpoints on their schedules until `shutdown` turns true
+    ///
+    /// On shutdown no further checks are started. Running checks get the configured grace period
+    /// to finish and are cancelled after it, then the history file is flushed.
+    pub async fn start_monitoring(&self, mut shutdown: watch::Receiver<bool>) {
         let check_interval = Duration::from_secs(self.config.monitoring.check_interval_seconds);
-        let connection_timeout =
-            Duration::from_secs(self.config.monitoring.connection_timeout_seconds);
 
         println!("🔍 Starting endpoint monitoring...");
         println!(
@@ -69,119 +503,1740 @@ impl MonitorService {
             self.config.monitoring.connection_timeout_seconds
         );
         println!("   Endpoints to monitor: {}", self.config.endpoints.len());
+        for endpoint in &self.config.endpoints {
+            if endpoint.check_interval_seconds.is_some()
+                || endpoint.connection_timeout_seconds.is_some()
+            {
+                println!(
+                    "   {}:{} - every {}s, timeout {}s",
+                    endpoint.address,
+                    endpoint.port,
+                    endpoint.check_interval(&self.config.monitoring).as_secs(),
+                    endpoint
+                        .connection_timeout(&self.config.monitoring)
+                        .as_secs()
+                );
+            }
+        }
 
-        loop {
-            let start_time = Instant::now();
+        let monitoring = &self.config.monitoring;
+        if monitoring.adaptive() {
+            let bound = |seconds: Option<u64>| {
+                seconds.map_or("the endpoint's interval".to_string(), |seconds| {
+                    format!("{}s", seconds)
+                })
+            };
+            println!(
+                "   Adaptive intervals: from {} to {}",
+                bound(monitoring.min_check_interval_seconds),
+                bound(monitoring.max_check_interval_seconds)
+            );
+        }
+        if let Some(limit) = monitoring.max_checks_per_minute {
+            println!("   Check budget: {} per minute", limit);
+        }
+
+        if let Some(tor_control) = &self.config.tor_control {
+            self.circuit_tracker.follow(tor_control.clone());
+        }
+        self.restore_history().await;
 
-            // Check all endpoints concurrently
-            let mut tasks = Vec::new();
+        // Each endpoint runs on its own interval. The proxies and Tor are looked at on the
+        // global interval, and checks starting in between use the latest results.
+        self.status_store.write().await.scheduler.interval_seconds = check_interval.as_secs();
+        let mut budget = self
+            .config
+            .monitoring
+            .max_checks_per_minute
+            .map(|limit| CheckBudget {
+                limit,
+                started: VecDeque::new(),
+            });
+        let mut next_cycle = Instant::now();
+        let mut round = Round::new(next_cycle, check_interval);
+        let mut reference_probes = None;
+        self.run_timed_cycle(
+            &mut next_cycle,
+            check_interval,
+            budget.as_mut(),
+            &mut reference_probes,
+        )
+        .await;
+        let mut endpoints: Vec<&Endpoint> = self.config.endpoints.iter().collect();
+        endpoints.shuffle(&mut rand::thread_rng());
+        let start = Instant::now();
+        let count = endpoints.len() as f64;
+        let mut schedule: Vec<ScheduledEndpoint> = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(position, endpoint)| {
+                let interval = endpoint.check_interval(&self.config.monitoring);
+                // Staggered endpoints take turns across their interval instead of all starting now
+                let offset = if self.config.monitoring.stagger_checks {
+                    interval.mul_f64(position as f64 / count)
+                } else {
+                    Duration::ZERO
+                };
+                ScheduledEndpoint {
+                    endpoint: endpoint.clone(),
+                    key: format!("{}:{}", endpoint.address, endpoint.port),
+                    base_interval: interval,
+                    interval,
+                    slot: start + offset,
+                    due: start + offset,
+                    next_check: start + offset,
+                    check: None,
+                }
+            })
+            .collect();
 
-            for endpoint in &self.config.endpoints {
-                let endpoint = endpoint.clone();
-                let status_store = self.status_store.clone();
+        loop {
+            if Instant::now() >= next_cycle {
+                if let Some(duration) = round.close() {
+                    record_cycle(&self.status_store, duration, check_interval).await;
+                }
+                round = Round::new(next_cycle, check_interval);
+                // The probes are bounded by the connection timeout, but shutdown needn't wait
+                tokio::select! {
+                    _ = self.run_timed_cycle(
+                        &mut next_cycle,
+                        check_interval,
+                        budget.as_mut(),
+                        &mut reference_probes,
+                    ) => {}
+                    Ok(()) = shutdown.changed() => {
+                        if *shutdown.borrow() {
+                            break;
+                        }
+                    }
+                }
+            }
 
-                let task = tokio::spawn(async move {
-                    Self::check_endpoint(endpoint, proxy_addr, connection_timeout, status_store)
-                        .await;
-                });
+            let now = Instant::now();
+            let mut skipped = 0;
+            for scheduled in schedule
+                .iter_mut()
+                .filter(|scheduled| scheduled.next_check <= now)
+            {
+                if scheduled
+                    .check
+                    .as_ref()
+                    .is_some_and(SpawnedCheck::is_queued)
+                {
+                    // The queued check covers this slot too, once it gets to start
+                    println!(
+                        "⏳ Check of {} is still waiting for a free slot",
+                        scheduled.key
+                    );
+                } else if scheduled
+                    .check
+                    .as_ref()
+                    .is_some_and(SpawnedCheck::is_running)
+                {
+                    println!(
+                        "⏳ Skipping check of {}, the previous one hasn't finished",
+                        scheduled.key
+                    );
+                    skipped += 1;
+                } else if let Some(Err(available)) = budget.as_mut().map(|budget| budget.take(now))
+                {
+                    // Leave the interval alone, the check only waits for room in the budget
+                    println!(
+                        "⏳ Delaying check of {}, the budget of {} checks per minute is used up",
+                        scheduled.key,
+                        self.config
+                            .monitoring
+                            .max_checks_per_minute
+                            .unwrap_or_default()
+                    );
+                    scheduled.next_check = available;
+                    continue;
+                } else {
+                    scheduled.check = Some(self.spawn_check(
+                        &scheduled.endpoint,
+                        Some(scheduled.due),
+                        Some(&round),
+                    ));
+                }
+                if self.config.monitoring.adaptive() {
+                    self.adapt_interval(scheduled).await;
+                }
+                let missed = scheduled.advance(now, self.config.monitoring.jitter_percent);
+                if missed > 0 {
+                    println!(
+                        "⏳ Skipping {} missed checks of {}, the scheduler fell behind",
+                        missed, scheduled.key
+                    );
+                    skipped += missed;
+                }
+            }
+            if skipped > 0 {
+                let mut store = self.status_store.write().await;
+                store.scheduler.skipped_checks += skipped;
+                store.scheduler.last_problem = Some(Utc::now());
+            }
 
-                tasks.push(task);
+            let next_wakeup = schedule
+                .iter()
+                .map(|scheduled| scheduled.next_check)
+                .fold(next_cycle, Instant::min);
+            // A dropped handle disables the shutdown branch, monitoring then runs for good
+            tokio::select! {
+                _ = sleep(next_wakeup.saturating_duration_since(Instant::now())) => {}
+                Ok(()) = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                }
             }
+        }
 
-            // Wait for all checks to complete
-            for task in tasks {
-                let _ = task.await;
+        if let Some(reference_probes) = reference_probes {
+            reference_probes.abort();
+        }
+        self.wind_down(schedule).await;
+    }
+
+    /// Drop queued checks, give running ones the grace period to finish, cancel the rest and
+    /// flush history
+    async fn wind_down(&self, schedule: Vec<ScheduledEndpoint>) {
+        let mut running = Vec::new();
+        for scheduled in schedule {
+            let Some(check) = scheduled.check else {
+                continue;
+            };
+            if check.is_queued() {
+                check.task.abort();
+                println!("✂️  Dropped queued check of {}", scheduled.key);
+            } else if check.is_running() {
+                running.push((scheduled.key, check.task));
             }
+        }
 
-            let check_duration = start_time.elapsed();
+        if !running.is_empty() {
+            let grace = self.config.monitoring.shutdown_grace();
             println!(
-                "✅ Completed monitoring cycle in {:.2}s",
-                check_duration.as_secs_f64()
+                "⏳ Waiting up to {}s for {} running checks to finish",
+                grace.as_secs(),
+                running.len()
             );
+            let deadline = Instant::now() + grace;
+            for (key, mut task) in running {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if timeout(remaining, &mut task).await.is_ok() {
+                    continue;
+                }
+                task.abort();
+                let _ = task.await;
+                println!("✂️  Cancelled check of {}", key);
+                // The check didn't get to record a result, so the last one stays
+                if let Some(endpoint_info) = self.status_store.write().await.endpoints.get_mut(&key)
+                {
+                    endpoint_info.checking = false;
+                }
+            }
+        }
 
-            // Sleep until next check interval
-            sleep(check_interval).await;
+        if let Some(history_log) = &self.history_log {
+            history_log.flush().await;
+            println!("📈 History written");
         }
+        println!("🛑 Monitoring stopped");
     }
 
-    async fn check_endpoint(
-        endpoint: Endpoint,
-        proxy_addr: SocketAddr,
-        connection_timeout: Duration,
-        status_store: SharedStatusStore,
+    /// Run the proxy and Tor probes of a cycle and start the reference probes in the background
+    ///
+    /// Cycles run at a fixed rate: `next_cycle` moves on by the interval from when the cycle was
+    /// due rather than from when it finished, skipping cycles that were missed. References still
+    /// being probed from the last cycle aren't probed again.
+    async fn run_timed_cycle(
+        &self,
+        next_cycle: &mut Instant,
+        check_interval: Duration,
+        budget: Option<&mut CheckBudget>,
+        reference_probes: &mut Option<JoinHandle<()>>,
     ) {
+        let available_proxies = self.prepare_cycle().await;
+        if reference_probes
+            .as_ref()
+            .is_some_and(|probes| !probes.is_finished())
+        {
+            println!("⏳ Skipping reference probes, the previous ones haven't finished");
+        } else {
+            *reference_probes = self.spawn_reference_probes(&available_proxies, budget);
+        }
+
+        *next_cycle += check_interval;
+        while *next_cycle <= Instant::now() {
+            *next_cycle += check_interval;
+        }
+    }
+
+    /// Move an endpoint's interval towards the minimum while it is having trouble, and towards
+    /// the maximum while it keeps working
+    ///
+    /// Goes by the last completed check, as the one just started hasn't finished yet.
+    async fn adapt_interval(&self, scheduled: &mut ScheduledEndpoint) {
+        let monitoring = &self.config.monitoring;
+        let base = scheduled.base_interval;
+        let min = monitoring
+            .min_check_interval_seconds
+            .map_or(base, |seconds| Duration::from_secs(seconds).min(base));
+        let max = monitoring
+            .max_check_interval_seconds
+            .map_or(base, |seconds| Duration::from_secs(seconds).max(base));
+
+        let mut store = self.status_store.write().await;
+        let Some(endpoint_info) = store.endpoints.get_mut(&scheduled.key) else {
+            return;
+        };
+        scheduled.interval = match &endpoint_info.status {
+            EndpointStatus::Online { .. } => scheduled
+                .interval
+                .max(base)
+                .mul_f64(INTERVAL_GROWTH)
+                .min(max),
+            EndpointStatus::Unknown | EndpointStatus::ProxyUnavailable { .. } => base,
+            EndpointStatus::Degraded { .. }
+            | EndpointStatus::Offline { .. }
+            | EndpointStatus::Flapping { .. }
+            | EndpointStatus::Failing { .. }
+            | EndpointStatus::Recovering { .. } => (scheduled.interval / 2).max(min),
+        };
+        endpoint_info.adapted_interval = Some(scheduled.interval);
+    }
+
+    /// Check every endpoint once and wait for all checks to complete
+    pub async fn run_cycle(&self) {
+        let start_time = Instant::now();
+        self.restore_history().await;
+        let available_proxies = self.prepare_cycle().await;
+        // Judge this cycle's failures against this cycle's references
+        if let Some(reference_probes) = self.spawn_reference_probes(&available_proxies, None) {
+            let _ = reference_probes.await;
+        }
+
+        // Check all endpoints concurrently, in random order so no endpoint is always queued last
+        let mut endpoints: Vec<&Endpoint> = self.config.endpoints.iter().collect();
+        endpoints.shuffle(&mut rand::thread_rng());
+        let tasks: Vec<_> = endpoints
+            .into_iter()
+            .map(|endpoint| self.spawn_check(endpoint, None, None))
+            .collect();
+
+        // Wait for all checks to complete
+        for check in tasks {
+            let _ = check.task.await;
+        }
+
+        let check_duration = start_time.elapsed();
+        println!(
+            "✅ Completed monitoring cycle in {:.2}s",
+            check_duration.as_secs_f64()
+        );
+        let check_interval = Duration::from_secs(self.config.monitoring.check_interval_seconds);
+        self.status_store.write().await.scheduler.interval_seconds = check_interval.as_secs();
+        record_cycle(&self.status_store, check_duration, check_interval).await;
+    }
+
+    /// Load the results of earlier runs from the history file, the first time only
+    async fn restore_history(&self) {
+        let history_writer = self.history_writer.lock().unwrap().take();
+        if let Some(history_writer) = history_writer
+            && let Err(error) = history_writer.start(&self.status_store).await
+        {
+            println!("⚠️  Not keeping history: {}", error);
+        }
+    }
+
+    /// Get everything checks rely on up to date, returning the proxies that are up
+    async fn prepare_cycle(&self) -> Vec<(String, Arc<dyn Dialer>)> {
+        let connection_timeout =
+            Duration::from_secs(self.config.monitoring.connection_timeout_seconds);
+
+        // Start from fresh circuits, so a stuck circuit from the last cycle can't skew this one
+        self.refresh_identity(connection_timeout).await;
+
+        // Probe the proxies and Tor first, so a dead proxy isn't mistaken for dead endpoints.
+        // Client auth keys are registered every cycle as Tor forgets them when it restarts.
+        let (available_proxies, _, _) = tokio::join!(
+            self.probe_proxies(connection_timeout),
+            self.check_tor(connection_timeout),
+            self.register_client_auth(connection_timeout)
+        );
+        available_proxies
+    }
+
+    /// Check an endpoint in the background, once the concurrency limit allows
+    ///
+    /// The proxies to use are picked when the check starts, so a check that waited for a permit
+    /// goes by the latest cycle's proxy probes. A check that starts well after `due` is counted
+    /// as late, and one that finishes `round` records it.
+    fn spawn_check(
+        &self,
+        endpoint: &Endpoint,
+        due: Option<Instant>,
+        round: Option<&Arc<Round>>,
+    ) -> SpawnedCheck {
+        let context = self.check_context(endpoint);
+        let round = round.cloned();
+        if let Some(round) = &round {
+            round.join();
+        }
+        let dialers = self.dialers.clone();
+        let endpoint = endpoint.clone();
+        let check_permits = self.check_permits.clone();
+        let started = Arc::new(AtomicBool::new(false));
+        let started_flag = started.clone();
+        let task = tokio::spawn(async move {
+            let _permit = match check_permits {
+                Some(check_permits) => check_permits.acquire_owned().await.ok(),
+                None => None,
+            };
+            started_flag.store(true, Ordering::Relaxed);
+            let vantages = dialers.vantages_for(&endpoint);
+            if let Some(lateness) = due
+                .map(|due| due.elapsed())
+                .filter(|&lateness| lateness > LATE_TOLERANCE)
+            {
+                println!(
+                    "🐌 Check of {}:{} started {:.1}s late",
+                    endpoint.address,
+                    endpoint.port,
+                    lateness.as_secs_f64()
+                );
+                let mut store = context.status_store.write().await;
+                let scheduler = &mut store.scheduler;
+                scheduler.late_checks += 1;
+                scheduler.max_lateness_ms =
+                    scheduler.max_lateness_ms.max(lateness.as_millis() as u64);
+                scheduler.last_problem = Some(Utc::now());
+            }
+            let status_store = context.status_store.clone();
+            Self::check_endpoint(endpoint, vantages, context).await;
+            if let Some(round) = round
+                && let Some(duration) = round.finish()
+            {
+                record_cycle(&status_store, duration, round.interval).await;
+            }
+        });
+        SpawnedCheck { task, started }
+    }
+
+    /// Probe the reference onions in the background, once each, returning the task that records
+    /// the results
+    ///
+    /// The references go through the proxies like endpoints in failover mode, so they see the
+    /// same Tor client the endpoints do. Each probe takes a concurrency permit and counts against
+    /// the check budget like any check; a reference that doesn't fit in the budget keeps its last
+    /// result. Aborting the task cancels the probes.
+    fn spawn_reference_probes(
+        &self,
+        available_proxies: &[NamedDialer],
+        mut budget: Option<&mut CheckBudget>,
+    ) -> Option<JoinHandle<()>> {
+        if self.config.references.is_empty() {
+            return None;
+        }
+
+        let dialer = failover_dialer(available_proxies);
+        let now = Instant::now();
+        let mut probes = JoinSet::new();
+        for (index, reference) in self.config.references.iter().enumerate() {
+            if let Some(budget) = budget.as_deref_mut()
+                && budget.take(now).is_err()
+            {
+                println!(
+                    "⏳ Skipping reference {} this cycle, the check budget is used up",
+                    reference.name
+                );
+                continue;
+            }
+
+            let endpoint = reference.endpoint();
+            let mut context = self.check_context(&endpoint);
+            // The reference is probed again next cycle, retries would only hold up the result
+            context.retries = 0;
+            let dialer = dialer.clone();
+            let check_permits = self.check_permits.clone();
+            probes.spawn(async move {
+                let _permit = match check_permits {
+                    Some(check_permits) => check_permits.acquire_owned().await.ok(),
+                    None => None,
+                };
+                let label = format!("reference {}", endpoint.name);
+                let (status, _) = Self::run_check(
+                    &label,
+                    dialer.as_deref().map_err(String::as_str),
+                    &endpoint,
+                    &context,
+                )
+                .await;
+                (index, status)
+            });
+        }
+
+        let status_store = self.status_store.clone();
+        let count = self.config.references.len();
+        Some(tokio::spawn(async move {
+            let mut statuses = vec![None; count];
+            while let Some(probe) = probes.join_next().await {
+                if let Ok((index, status)) = probe {
+                    statuses[index] = Some(status);
+                }
+            }
+
+            let mut store = status_store.write().await;
+            for (reference, status) in store.references.iter_mut().zip(statuses) {
+                if let Some(status) = status {
+                    reference.status = status;
+                    reference.last_check = Some(Utc::now());
+                }
+            }
+            if store.local_problem() {
+                println!(
+                    "⚠️  Every reference onion failed: endpoint failures are likely a local or Tor problem"
+                );
+            }
+        }))
+    }
+
+    /// Everything a check of `endpoint` needs to know besides how to reach it
+    fn check_context(&self, endpoint: &Endpoint) -> CheckContext {
+        CheckContext {
+            connection_timeout: endpoint.connection_timeout(&self.config.monitoring),
+            retries: self.config.monitoring.retries,
+            retry_backoff: self.config.monitoring.retry_backoff(),
+            failures_before_offline: self.config.monitoring.failures_before_offline(),
+            successes_before_online: self.config.monitoring.successes_before_online(),
+            degraded_response_ms: self.config.monitoring.degraded_response_ms,
+            flapping_changes: self.config.monitoring.flapping_changes,
+            flapping_window: self.config.monitoring.flapping_window(),
+            latency_window: self.config.monitoring.latency_window(),
+            anomaly_deviations: self.config.monitoring.anomaly_deviations(),
+            status_store: self.status_store.clone(),
+            events: self.events.clone(),
+            history_log: self.history_log.clone(),
+            descriptor_fetch: self
+                .config
+                .tor_control
+                .clone()
+                .filter(|tor_control| tor_control.fetch_descriptors),
+            descriptor_fetches: self.descriptor_fetches.clone(),
+            circuit_tracker: self
+                .config
+                .tor_control
+                .as_ref()
+                .map(|_| self.circuit_tracker.clone()),
+        }
+    }
+
+    /// Probe every proxy and record its health, returning the proxies that are up
+    async fn probe_proxies(&self, probe_timeout: Duration) -> Vec<(String, Arc<dyn Dialer>)> {
+        let tasks: Vec<_> = self
+            .dialers
+            .proxies
+            .iter()
+            .map(|(name, dialer)| {
+                let dialer = dialer.clone();
+                let name = name.clone();
+                tokio::spawn(async move {
+                    let start_time = Instant::now();
+                    let health = match tokio::time::timeout(probe_timeout, dialer.probe()).await {
+                        Ok(Ok(())) => ProxyHealth::Up {
+                            response_time_ms: start_time.elapsed().as_millis() as u64,
+                        },
+                        Ok(Err(DialError::ProxyUnavailable(reason))) => {
+                            ProxyHealth::Down { error: reason }
+                        }
+                        Ok(Err(error)) => ProxyHealth::Down {
+                            error: error.to_string(),
+                        },
+                        Err(_) => ProxyHealth::Down {
+                            error: format!("Probe timeout ({}s)", probe_timeout.as_secs()),
+                        },
+                    };
+                    if let ProxyHealth::Down { error } = &health {
+                        println!("🧅 Proxy {} is down: {}", name, error);
+                    }
+                    ProxyStatus {
+                        name,
+                        health,
+                        last_check: Some(Utc::now()),
+                    }
+                })
+            })
+            .collect();
+
+        let mut proxies = Vec::new();
+        for task in tasks {
+            if let Ok(proxy_status) = task.await {
+                proxies.push(proxy_status);
+            }
+        }
+
+        let available: Vec<_> = self
+            .dialers
+            .proxies
+            .iter()
+            .filter(|(name, _)| {
+                proxies
+                    .iter()
+                    .any(|proxy| &proxy.name == name && !proxy.is_down())
+            })
+            .cloned()
+            .collect();
+
+        self.status_store.write().await.proxies = proxies;
+        *self.dialers.available.lock().unwrap() = available.clone();
+
+        available
+    }
+
+    /// Move this cycle's streams off the previous cycle's circuits, as configured
+    async fn refresh_identity(&self, command_timeout: Duration) {
+        match self.config.monitoring.fresh_identity {
+            FreshIdentity::Off => {}
+            FreshIdentity::Isolate => {
+                let token = format!("cycle-{}", Utc::now().timestamp_millis());
+                for (_, dialer) in &self.dialers.proxies {
+                    dialer.isolate(&token);
+                }
+            }
+            FreshIdentity::Newnym => {
+                let Some(tor_control) = &self.config.tor_control else {
+                    return;
+                };
+                {
+                    let mut last_newnym = self.last_newnym.lock().unwrap();
+                    if last_newnym.is_some_and(|sent| sent.elapsed() < NEWNYM_RATE_LIMIT) {
+                        println!("🧅 Skipping NEWNYM, the last one was less than 10s ago");
+                        return;
+                    }
+                    *last_newnym = Some(Instant::now());
+                }
+
+                match tokio::time::timeout(command_timeout, signal_newnym(tor_control)).await {
+                    Ok(Ok(())) => println!("🧅 Sent NEWNYM, this cycle uses fresh circuits"),
+                    Ok(Err(error)) => println!("⚠️  NEWNYM failed: {}", error),
+                    Err(_) => println!("⚠️  NEWNYM timed out"),
+                }
+            }
+        }
+    }
+
+    /// Read Tor's status from the control port, if one is configured
+    async fn check_tor(&self, query_timeout: Duration) {
+        let Some(tor_control) = &self.config.tor_control else {
+            return;
+        };
+
+        let tor_status =
+            match tokio::time::timeout(query_timeout, fetch_tor_status(tor_control)).await {
+                Ok(Ok(tor_status)) => tor_status,
+                Ok(Err(error)) => TorStatus::unreachable(error.to_string()),
+                Err(_) => TorStatus::unreachable(format!(
+                    "Control port timeout ({}s)",
+                    query_timeout.as_secs()
+                )),
+            };
+        if let Some(problem) = tor_status.problem() {
+            println!("🧅 Tor is not ready: {}", problem);
+        }
+
+        self.status_store.write().await.tor = Some(tor_status);
+    }
+
+    /// Hand the configured client authorization keys to Tor before the endpoints are checked
+    async fn register_client_auth(&self, command_timeout: Duration) {
+        let Some(tor_control) = &self.config.tor_control else {
+            return;
+        };
+        let keys: Vec<_> = self
+            .config
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                Some((endpoint.onion_address()?, endpoint.client_auth_key.clone()?))
+            })
+            .collect();
+        if keys.is_empty() {
+            return;
+        }
+
+        match tokio::time::timeout(command_timeout, register_client_auth(tor_control, &keys)).await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => println!("⚠️  Failed to register client auth keys: {}", error),
+            Err(_) => println!(
+                "⚠️  Registering client auth keys timed out ({}s)",
+                command_timeout.as_secs()
+            ),
+        }
+    }
+
+    async fn check_endpoint(endpoint: Endpoint, vantages: Vec<Vantage>, context: CheckContext) {
         let key = format!("{}:{}", endpoint.address, endpoint.port);
         let check_time = Utc::now();
+        let check_start = Instant::now();
+        let status_store = &context.status_store;
 
-        // Update status to "Checking"
+        // Flag the check as running, keeping the last result on display
         {
             let mut store = status_store.write().await;
-            if let Some(endpoint_info) = store.get_mut(&key) {
-                endpoint_info.status = EndpointStatus::Checking;
+            if let Some(endpoint_info) = store.endpoints.get_mut(&key) {
+                endpoint_info.checking = true;
             }
         }
 
         println!("🔗 Checking {}", key);
 
+        let (status, timings, vantage_results) = if let [(_, dialer)] = vantages.as_slice() {
+            let (status, timings) = Self::run_check(
+                &key,
+                dialer.as_deref().map_err(String::as_str),
+                &endpoint,
+                &context,
+            )
+            .await;
+            (status, timings, Vec::new())
+        } else {
+            // Check through every vantage at once and keep each result
+            let tasks: Vec<_> = vantages
+                .into_iter()
+                .map(|(proxy, dialer)| {
+                    let endpoint = endpoint.clone();
+                    let context = context.clone();
+                    let label = format!("{} via {}", key, proxy);
+                    tokio::spawn(async move {
+                        let (status, timings) = Self::run_check(
+                            &label,
+                            dialer.as_deref().map_err(String::as_str),
+                            &endpoint,
+                            &context,
+                        )
+                        .await;
+                        VantageResult {
+                            proxy,
+                            status,
+                            timings,
+                        }
+                    })
+                })
+                .collect();
+
+            let mut results = Vec::new();
+            for task in tasks {
+                if let Ok(result) = task.await {
+                    results.push(result);
+                }
+            }
+
+            // The endpoint is up if any vantage reached it; report the fastest healthy one
+            let best = results
+                .iter()
+                .filter_map(|result| {
+                    let response_time_ms = result.status.response_time_ms()?;
+                    let degraded = matches!(result.status, EndpointStatus::Degraded { .. });
+                    Some(((degraded, response_time_ms), result))
+                })
+                .min_by_key(|(rank, _)| *rank)
+                .map(|(_, result)| result)
+                .or_else(|| {
+                    results.iter().find(|result| {
+                        !matches!(result.status, EndpointStatus::ProxyUnavailable { .. })
+                    })
+                })
+                .or(results.first());
+            let (status, timings) = match best {
+                Some(result) => (result.status.clone(), result.timings.clone()),
+                None => (EndpointStatus::Unknown, CheckTimings::default()),
+            };
+            (status, timings, results)
+        };
+
+        let failed = matches!(status, EndpointStatus::Offline { .. });
+
+        // Find the circuits that carried the probe
+        let circuits = match &context.circuit_tracker {
+            Some(circuit_tracker) if endpoint.mode == CheckMode::Proxy => {
+                sleep(CIRCUIT_EVENT_GRACE).await;
+                let service_id = endpoint.onion_address().map(|onion| onion.service_id());
+                circuit_tracker.circuits_for(
+                    timings.proxy_source_addr,
+                    service_id.as_deref(),
+                    check_start,
+                )
+            }
+            _ => Vec::new(),
+        };
+
+        // Update the status store
+        {
+            let mut store = status_store.write().await;
+            // Direct checks don't go through Tor, so the references say nothing about them
+            let proxied = endpoint.mode == CheckMode::Proxy;
+            let local_problem = proxied && store.local_problem();
+            let reference_latency_ms = store.reference_latency_ms().filter(|_| proxied);
+            if let Some(endpoint_info) = store.endpoints.get_mut(&key) {
+                if status.response_time_ms().is_some() {
+                    endpoint_info.descriptor = None;
+                }
+                if endpoint_info.history.len() == HISTORY_LENGTH {
+                    endpoint_info.history.pop_front();
+                }
+                endpoint_info.history.push_back(CheckRecord {
+                    checked_at: check_time,
+                    status: status.clone(),
+                    timings: timings.clone(),
+                    circuits,
+                    local_problem,
+                });
+                endpoint_info.relative_latency = status
+                    .response_time_ms()
+                    .zip(reference_latency_ms.filter(|&latency_ms| latency_ms > 0))
+                    .map(|(response_time_ms, latency_ms)| {
+                        response_time_ms as f64 / latency_ms as f64
+                    });
+                endpoint_info.local_problem = local_problem && status.is_up() == Some(false);
+                if let Some(up) = status.is_up() {
+                    let sample = Sample {
+                        at: check_time,
+                        up,
+                        response_time_ms: status.response_time_ms(),
+                    };
+                    if let Some(history_log) = &context.history_log {
+                        history_log.record(&key, &sample);
+                    }
+                    push_sample(&mut endpoint_info.samples, sample);
+                    endpoint_info.latency = latency_stats(
+                        &endpoint_info.samples,
+                        check_time,
+                        TimeDelta::from_std(context.latency_window).unwrap_or(TimeDelta::MAX),
+                    );
+                }
+                match status.response_time_ms() {
+                    Some(response_time_ms) => {
+                        track_baseline(endpoint_info, &key, response_time_ms, &context)
+                    }
+                    None => endpoint_info.anomaly = None,
+                }
+                apply_result(endpoint_info, &key, status, &context, Utc::now());
+                endpoint_info.checking = false;
+                endpoint_info.last_check = Some(check_time);
+                endpoint_info.last_timings = Some(timings);
+                endpoint_info.vantages = vantage_results;
+            }
+        }
+
+        // Ask the HSDirs about a failed onion service, to tell a service that isn't publishing
+        // from one that is published but unreachable. The fetch can take a while, so it runs on
+        // its own rather than holding up the endpoint's next check.
+        if let (true, Some(tor_control), Some(onion)) =
+            (failed, &context.descriptor_fetch, endpoint.onion_address())
+            && context
+                .descriptor_fetches
+                .lock()
+                .unwrap()
+                .insert(key.clone())
+        {
+            let tor_control = tor_control.clone();
+            let descriptor_fetches = context.descriptor_fetches.clone();
+            let status_store = status_store.clone();
+            tokio::spawn(async move {
+                println!("📜 Fetching descriptor of {}", key);
+                let diagnostics =
+                    fetch_descriptor(&tor_control, &onion, DESCRIPTOR_FETCH_TIMEOUT).await;
+                println!("📜 {} - {}", key, diagnostics.summary());
+
+                let mut store = status_store.write().await;
+                // A check may have reached the service in the meantime
+                if let Some(endpoint_info) = store.endpoints.get_mut(&key)
+                    && endpoint_info.status.response_time_ms().is_none()
+                {
+                    endpoint_info.descriptor = Some(diagnostics);
+                }
+                descriptor_fetches.lock().unwrap().remove(&key);
+            });
+        }
+    }
+
+    /// Check an endpoint through one dialer, retrying failed attempts as configured
+    ///
+    /// When the proxy is known to be down the endpoint isn't dialed at all.
+    async fn run_check(
+        label: &str,
+        dialer: Result<&dyn Dialer, &str>,
+        endpoint: &Endpoint,
+        context: &CheckContext,
+    ) -> (EndpointStatus, CheckTimings) {
+        let dialer = match dialer {
+            Ok(dialer) => dialer,
+            Err(reason) => {
+                println!("⚪ {} - Unknown: {}", label, reason);
+                let reason = reason.to_string();
+                return (
+                    EndpointStatus::ProxyUnavailable { reason },
+                    CheckTimings::default(),
+                );
+            }
+        };
+
+        let mut backoff = context.retry_backoff.min(MAX_RETRY_BACKOFF);
+        for retry in 1..=context.retries {
+            let (status, timings) = Self::attempt_check(label, dialer, endpoint, context).await;
+            if !matches!(status, EndpointStatus::Offline { .. }) {
+                return (status, timings);
+            }
+
+            println!(
+                "🔁 Retrying {} in {}ms ({}/{})",
+                label,
+                backoff.as_millis(),
+                retry,
+                context.retries
+            );
+            sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(MAX_RETRY_BACKOFF);
+        }
+
+        Self::attempt_check(label, dialer, endpoint, context).await
+    }
+
+    /// Make one attempt at reaching an endpoint, giving up after the connection timeout
+    async fn attempt_check(
+        label: &str,
+        dialer: &dyn Dialer,
+        endpoint: &Endpoint,
+        context: &CheckContext,
+    ) -> (EndpointStatus, CheckTimings) {
+        let connection_timeout = context.connection_timeout;
+        let mut timings = CheckTimings::default();
+
         // Perform the actual connection check with timeout
         let status = match tokio::time::timeout(
             connection_timeout,
-            Self::test_connection(proxy_addr, &endpoint.address, endpoint.port),
+            Self::test_connection(dialer, endpoint, &mut timings),
         )
         .await
         {
-            Ok(Ok(response_time)) => {
-                println!("✅ {} - Online ({}ms)", key, response_time);
-                EndpointStatus::Online {
-                    response_time_ms: response_time,
+            Ok(Ok(response_time)) => match context.degraded_response_ms {
+                Some(limit) if response_time > limit => {
+                    println!("🟡 {} - Degraded ({}ms)", label, response_time);
+                    EndpointStatus::Degraded {
+                        response_time_ms: response_time,
+                        reason: format!("Slower than {}ms", limit),
+                    }
+                }
+                _ => {
+                    println!("✅ {} - Online ({}ms)", label, response_time);
+                    EndpointStatus::Online {
+                        response_time_ms: response_time,
+                    }
+                }
+            },
+            Ok(Err(CheckError::Dial(DialError::ProxyUnavailable(reason)))) => {
+                println!("⚪ {} - Unknown: {}", label, reason);
+                EndpointStatus::ProxyUnavailable { reason }
+            }
+            // Reachable, so the directory is there even if it doesn't speak the protocol
+            Ok(Err(error @ CheckError::Handshake(_))) => {
+                println!("🟡 {} - Degraded: {}", label, error);
+                EndpointStatus::Degraded {
+                    response_time_ms: timings.connected_ms(endpoint.mode).unwrap_or_default(),
+                    reason: error.to_string(),
                 }
             }
             Ok(Err(error)) => {
-                println!("❌ {} - Offline: {}", key, error);
+                println!("❌ {} - Offline: {}", label, error);
                 EndpointStatus::Offline {
                     error: error.to_string(),
+                    kind: error.kind(),
+                }
+            }
+            Err(_) if endpoint.handshake && timings.connected_ms(endpoint.mode).is_some() => {
+                let reason = format!(
+                    "Handshake did not complete within {}s",
+                    connection_timeout.as_secs()
+                );
+                println!("🟡 {} - Degraded: {}", label, reason);
+                EndpointStatus::Degraded {
+                    response_time_ms: timings.connected_ms(endpoint.mode).unwrap_or_default(),
+                    reason,
                 }
             }
             Err(_) => {
                 let timeout_msg = format!("Connection timeout ({}s)", connection_timeout.as_secs());
-                println!("⏰ {} - {}", key, timeout_msg);
-                EndpointStatus::Offline { error: timeout_msg }
+                println!("⏰ {} - {}", label, timeout_msg);
+                EndpointStatus::Offline {
+                    error: timeout_msg,
+                    kind: FailureKind::Timeout,
+                }
             }
         };
 
-        // Update the status store
-        {
-            let mut store = status_store.write().await;
-            if let Some(endpoint_info) = store.get_mut(&key) {
-                endpoint_info.status = status;
-                endpoint_info.last_check = Some(check_time);
-            }
-        }
+        (status, timings)
     }
 
     async fn test_connection(
-        proxy_addr: SocketAddr,
-        target_host: &str,
-        target_port: u16,
-    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        dialer: &dyn Dialer,
+        endpoint: &Endpoint,
+        timings: &mut CheckTimings,
+    ) -> Result<u64, CheckError> {
         let start_time = Instant::now();
 
-        // Attempt to connect via SOCKS5
-        let _stream = socks5_connect(proxy_addr, target_host, target_port).await?;
+        // Resolve clearnet hostnames up front so DNS failures are reported on their own
+        let target_host = if needs_resolution(&endpoint.address) {
+            let phase_start = Instant::now();
+            match dialer.resolve(&endpoint.address).await {
+                Ok(address) => {
+                    timings.resolve_ms = Some(phase_start.elapsed().as_millis() as u64);
+                    address.to_string()
+                }
+                Err(DialError::ResolveUnsupported) => endpoint.address.clone(),
+                Err(error) => return Err(error.into()),
+            }
+        } else {
+            endpoint.address.clone()
+        };
+
+        // Attempt to connect through the endpoint's dialer
+        let mut stream = dialer.dial(&target_host, endpoint.port, timings).await?;
 
         let response_time = start_time.elapsed().as_millis() as u64;
+
+        if endpoint.handshake {
+            joinmarket_handshake(&mut stream, timings).await?;
+        }
+
         Ok(response_time)
     }
+
+    /// Run monitoring in a background task and return the shared status store
+    pub fn spawn(self) -> SharedStatusStore {
+        self.start().status_store()
+    }
+
+    /// Run monitoring in a background task that can be shut down through the returned handle
+    ///
+    /// Dropping the handle leaves monitoring running.
+    pub fn start(self) -> MonitorHandle {
+        let status_store = self.get_status_store();
+        let (shutdown, receiver) = watch::channel(false);
+
+        let task = tokio::spawn(async move {
+            self.start_monitoring(receiver).await;
+        });
+
+        MonitorHandle {
+            status_store,
+            shutdown: Arc::new(shutdown),
+            task,
+        }
+    }
 }
 
-pub async fn start_background_monitoring(config: Config) -> SharedStatusStore {
-    let monitor = MonitorService::new(config);
-    let status_store = monitor.get_status_store();
+/// Count a round of checks and whether it took longer than the interval
+async fn record_cycle(status_store: &SharedStatusStore, duration: Duration, interval: Duration) {
+    let overrun = duration > interval;
+    if overrun {
+        println!(
+            "⚠️  Checks due within a {}s interval took {:.2}s to finish",
+            interval.as_secs(),
+            duration.as_secs_f64()
+        );
+    }
+
+    let mut store = status_store.write().await;
+    let scheduler = &mut store.scheduler;
+    scheduler.cycles += 1;
+    scheduler.last_cycle_ms = Some(duration.as_millis() as u64);
+    if overrun {
+        scheduler.overruns += 1;
+        scheduler.last_problem = Some(Utc::now());
+    }
+}
+
+/// Show a check result on the endpoint, only switching between online and offline once enough
+/// checks in a row agree, and record the change at `now` if the status changed
+fn apply_result(
+    endpoint_info: &mut EndpointInfo,
+    key: &str,
+    result: EndpointStatus,
+    context: &CheckContext,
+    now: DateTime<Utc>,
+) {
+    let was_offline = endpoint_info.confirmed_offline;
+    let status = match result {
+        EndpointStatus::Online { response_time_ms }
+        | EndpointStatus::Degraded {
+            response_time_ms, ..
+        } => {
+            endpoint_info.consecutive_failures = 0;
+            endpoint_info.consecutive_successes += 1;
+            let successes = endpoint_info.consecutive_successes;
+            if endpoint_info.confirmed_offline && successes < context.successes_before_online {
+                EndpointStatus::Recovering {
+                    response_time_ms,
+                    successes,
+                    threshold: context.successes_before_online,
+                }
+            } else {
+                if endpoint_info.confirmed_offline {
+                    println!(
+                        "🟢 {} is back online after {} successful checks",
+                        key, successes
+                    );
+                }
+                endpoint_info.confirmed_offline = false;
+                result
+            }
+        }
+        EndpointStatus::Offline { error, kind } => {
+            endpoint_info.consecutive_successes = 0;
+            endpoint_info.consecutive_failures += 1;
+            let failures = endpoint_info.consecutive_failures;
+            if !endpoint_info.confirmed_offline && failures < context.failures_before_offline {
+                EndpointStatus::Failing {
+                    error,
+                    kind,
+                    failures,
+                    threshold: context.failures_before_offline,
+                }
+            } else {
+                if !endpoint_info.confirmed_offline && context.failures_before_offline > 1 {
+                    println!("🔴 {} is offline after {} failed checks", key, failures);
+                }
+                endpoint_info.confirmed_offline = true;
+                EndpointStatus::Offline { error, kind }
+            }
+        }
+        // Checks that couldn't reach a verdict don't count either way
+        other => other,
+    };
+
+    let previous = match &endpoint_info.status {
+        EndpointStatus::Flapping { last, .. } => last.as_ref(),
+        previous => previous,
+    };
+    let mut up_down = false;
+    if status.is_up().is_some() {
+        if endpoint_info.since.is_none() {
+            endpoint_info.since = Some(now);
+        } else if was_offline != endpoint_info.confirmed_offline {
+            endpoint_info.since = Some(now);
+            up_down = true;
+        }
+    }
+    if previous.status_text() != status.status_text() {
+        if endpoint_info.transitions.len() == TRANSITION_HISTORY {
+            endpoint_info.transitions.pop_front();
+        }
+        endpoint_info.transitions.push_back(Transition {
+            at: now,
+            from: previous.status_text().to_string(),
+            to: status.status_text().to_string(),
+            up_down,
+        });
+    }
+
+    // Going up and down too often says more than whichever state the endpoint is in right now
+    let was_flapping = matches!(endpoint_info.status, EndpointStatus::Flapping { .. });
+    let window_start = now - context.flapping_window;
+    let changes = endpoint_info
+        .transitions
+        .iter()
+        .filter(|transition| transition.up_down && transition.at >= window_start)
+        .count();
+    endpoint_info.status = match context.flapping_changes {
+        Some(limit) if changes >= limit => {
+            if !was_flapping {
+                println!(
+                    "🟣 {} is flapping ({} up/down changes in {}s)",
+                    key,
+                    changes,
+                    context.flapping_window.as_secs()
+                );
+            }
+            EndpointStatus::Flapping {
+                changes,
+                window_seconds: context.flapping_window.as_secs(),
+                last: Box::new(status),
+            }
+        }
+        _ => status,
+    };
+}
+
+/// Flag a response time far above the endpoint's baseline, then learn from it
+///
+/// Slow responses still feed the baseline, so it follows an endpoint whose normal changes.
+fn track_baseline(
+    endpoint_info: &mut EndpointInfo,
+    key: &str,
+    response_time_ms: u64,
+    context: &CheckContext,
+) {
+    let Some(baseline) = &mut endpoint_info.baseline else {
+        endpoint_info.baseline = Some(LatencyBaseline::new(response_time_ms));
+        return;
+    };
+
+    endpoint_info.anomaly = baseline
+        .is_anomaly(response_time_ms, context.anomaly_deviations)
+        .then(|| LatencyAnomaly {
+            response_time_ms,
+            baseline_ms: baseline.mean_ms.round() as u64,
+        });
+    if let Some(anomaly) = endpoint_info.anomaly {
+        println!(
+            "🐢 {} is slower than usual ({}ms, usually ~{}ms)",
+            key, anomaly.response_time_ms, anomaly.baseline_ms
+        );
+        let _ = context.events.send(MonitorEvent::SlowerThanUsual {
+            endpoint: key.to_string(),
+            name: endpoint_info.endpoint.name.clone(),
+            response_time_ms,
+            baseline_ms: anomaly.baseline_ms,
+        });
+    }
+    baseline.update(response_time_ms);
+}
+
+/// A dialer failing over between the proxies that are up, or why there is none
+fn failover_dialer(
+    available_proxies: &[(String, Arc<dyn Dialer>)],
+) -> Result<Arc<dyn Dialer>, String> {
+    if available_proxies.is_empty() {
+        Err("all proxies are down".to_string())
+    } else {
+        Ok(Arc::new(FailoverDialer::new(available_proxies.to_vec())))
+    }
+}
+
+/// `interval` moved by a random amount of up to `percent` of it, either way
+fn jittered(interval: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return interval;
+    }
+    let spread = f64::from(percent) / 100.0;
+    interval.mul_f64(1.0 + rand::thread_rng().gen_range(-spread..=spread))
+}
+
+/// Whether `address` is a hostname that has to be resolved (i.e. not an IP or onion address)
+fn needs_resolution(address: &str) -> bool {
+    address.parse::<IpAddr>().is_err() && !address.ends_with(".onion")
+}
 
+pub async fn start_background_monitoring(config: Config) -> SharedStatusStore {
     // Start monitoring in background task
-    tokio::spawn(async move {
-        monitor.start_monitoring().await;
-    });
+    MonitorService::new(config).spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_default_config;
+    use crate::dialer::{ScriptedDialer, ScriptedOutcome};
+    use crate::types::{MonitoringConfig, ReferenceOnion};
+
+    fn endpoint(address: &str, mode: CheckMode) -> Endpoint {
+        Endpoint {
+            name: address.to_string(),
+            address: address.to_string(),
+            port: 5222,
+            mode,
+            handshake: false,
+            client_auth_key: None,
+            check_interval_seconds: None,
+            connection_timeout_seconds: None,
+        }
+    }
+
+    fn config(endpoints: Vec<Endpoint>) -> Config {
+        let mut config = create_default_config();
+        config.endpoints = endpoints;
+        config
+    }
+
+    async fn status_of(monitor: &MonitorService, address: &str) -> EndpointStatus {
+        let store = monitor.status_store.read().await;
+        store.endpoints[&format!("{}:5222", address)].status.clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_cycle_reports_scripted_outcomes() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang));
+        proxy.push(
+            "192.0.2.1",
+            5222,
+            ScriptedOutcome::Connect {
+                delay: Duration::from_millis(250),
+            },
+        );
+        proxy.push(
+            "192.0.2.2",
+            5222,
+            ScriptedOutcome::Fail {
+                delay: Duration::ZERO,
+                error: "connection refused".to_string(),
+            },
+        );
+        let direct = Arc::new(ScriptedDialer::new(ScriptedOutcome::Connect {
+            delay: Duration::ZERO,
+        }));
+        let monitor = MonitorService::with_dialers(
+            config(vec![
+                endpoint("192.0.2.1", CheckMode::Proxy),
+                endpoint("192.0.2.2", CheckMode::Proxy),
+                endpoint("192.0.2.3", CheckMode::Proxy),
+                endpoint("192.0.2.4", CheckMode::Direct),
+            ]),
+            proxy.clone(),
+            direct.clone(),
+        );
+
+        monitor.run_cycle().await;
+
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.1").await,
+            EndpointStatus::Online { .. }
+        ));
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.2").await,
+            EndpointStatus::Offline {
+                kind: FailureKind::Connect,
+                ..
+            }
+        ));
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.3").await,
+            EndpointStatus::Offline {
+                kind: FailureKind::Timeout,
+                ..
+            }
+        ));
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.4").await,
+            EndpointStatus::Online { .. }
+        ));
+        assert_eq!(direct.dialed(), vec!["192.0.2.4:5222"]);
+        assert!(!proxy.dialed().contains(&"192.0.2.4:5222".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_check_uses_proxies_found_when_it_starts() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Connect {
+            delay: Duration::from_secs(5),
+        }));
+        let mut config = config(vec![
+            endpoint("192.0.2.1", CheckMode::Proxy),
+            endpoint("192.0.2.2", CheckMode::Proxy),
+        ]);
+        config.monitoring.max_concurrent_checks = Some(1);
+        let monitor = MonitorService::with_dialers(
+            config,
+            proxy.clone(),
+            Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang)),
+        );
+        monitor.prepare_cycle().await;
+
+        let first = monitor.spawn_check(&monitor.config.endpoints[0], None, None);
+        let second = monitor.spawn_check(&monitor.config.endpoints[1], None, None);
+        sleep(Duration::from_millis(1)).await;
+        assert!(first.is_running());
+        assert!(second.is_queued());
+        assert!(!second.is_running());
+
+        // The next cycle finds the proxy down while the second check is still queued
+        proxy.set_probe_failure(Some("connection refused".to_string()));
+        monitor.prepare_cycle().await;
+        first.task.await.unwrap();
+        second.task.await.unwrap();
+
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.1").await,
+            EndpointStatus::Online { .. }
+        ));
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.2").await,
+            EndpointStatus::ProxyUnavailable { .. }
+        ));
+        assert_eq!(proxy.dialed(), vec!["192.0.2.1:5222"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_references_mark_the_same_cycle_as_local_problem() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Fail {
+            delay: Duration::ZERO,
+            error: "connection refused".to_string(),
+        }));
+        let mut config = config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]);
+        config.references = vec![ReferenceOnion {
+            name: "reference".to_string(),
+            address: "192.0.2.9".to_string(),
+            port: 80,
+            handshake: false,
+        }];
+        let monitor = MonitorService::with_dialers(
+            config,
+            proxy.clone(),
+            Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang)),
+        );
+
+        monitor.run_cycle().await;
+
+        assert_eq!(proxy.dialed(), vec!["192.0.2.9:80", "192.0.2.1:5222"]);
+        let store = monitor.status_store.read().await;
+        let endpoint_info = &store.endpoints["192.0.2.1:5222"];
+        assert!(endpoint_info.local_problem);
+        assert!(endpoint_info.history.back().unwrap().local_problem);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_reference_holds_up_neither_checks_nor_shutdown() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Connect {
+            delay: Duration::ZERO,
+        }));
+        proxy.push("192.0.2.9", 80, ScriptedOutcome::Hang);
+        let mut config = config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]);
+        config.monitoring.retries = 3;
+        config.references = vec![ReferenceOnion {
+            name: "reference".to_string(),
+            address: "192.0.2.9".to_string(),
+            port: 80,
+            handshake: false,
+        }];
+        let monitor = MonitorService::with_dialers(
+            config,
+            proxy.clone(),
+            Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang)),
+        );
+        let connection_timeout =
+            Duration::from_secs(monitor.config.monitoring.connection_timeout_seconds);
+        let handle = monitor.start();
+
+        sleep(Duration::from_secs(1)).await;
+        let store = handle.status_store();
+        assert!(matches!(
+            store.read().await.endpoints["192.0.2.1:5222"].status,
+            EndpointStatus::Online { .. }
+        ));
+        assert!(matches!(
+            store.read().await.references[0].status,
+            EndpointStatus::Unknown
+        ));
+
+        let start = tokio::time::Instant::now();
+        handle.shutdown().await;
+        assert!(start.elapsed() < connection_timeout);
+        // Probed once, without retries
+        assert_eq!(
+            proxy
+                .dialed()
+                .iter()
+                .filter(|target| *target == "192.0.2.9:80")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn round_lasts_until_its_last_check_finishes() {
+        let interval = Duration::from_secs(10);
+        let round = Round::new(Instant::now() - Duration::from_secs(15), interval);
+        round.join();
+        round.join();
+
+        assert_eq!(round.finish(), None);
+        // Still running when the interval ends
+        assert_eq!(round.close(), None);
+        let duration = round.finish().unwrap();
+        assert!(duration >= Duration::from_secs(15) && duration > interval);
+    }
+
+    #[test]
+    fn round_finished_before_its_interval_ends_on_close() {
+        let round = Round::new(Instant::now(), Duration::from_secs(10));
+        round.join();
+
+        assert_eq!(round.finish(), None);
+        assert!(round.close().unwrap() < Duration::from_secs(10));
+    }
 
-    status_store
+    #[test]
+    fn round_without_checks_is_not_measured() {
+        let round = Round::new(Instant::now(), Duration::from_secs(10));
+        assert_eq!(round.close(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn signal_stops_monitoring_without_waiting_for_shutdown() {
+        let scripted = Arc::new(ScriptedDialer::new(ScriptedOutcome::Connect {
+            delay: Duration::ZERO,
+        }));
+        let config = config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]);
+        let handle = MonitorService::with_dialers(config, scripted.clone(), scripted).start();
+        let (signal, signalled) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(handle.stop_on(async {
+            let _ = signalled.await;
+        }));
+
+        sleep(Duration::from_secs(1)).await;
+        assert!(!handle.task.is_finished());
+        signal.send(()).unwrap();
+        sleep(Duration::from_secs(1)).await;
+        assert!(handle.task.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn huge_retry_backoff_is_capped() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Fail {
+            delay: Duration::ZERO,
+            error: "connection refused".to_string(),
+        }));
+        let mut config = config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]);
+        config.monitoring.retries = 3;
+        config.monitoring.retry_backoff_ms = Some(u64::MAX);
+        let monitor = MonitorService::with_dialers(
+            config,
+            proxy.clone(),
+            Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang)),
+        );
+
+        let start = tokio::time::Instant::now();
+        monitor.run_cycle().await;
+
+        assert_eq!(start.elapsed(), MAX_RETRY_BACKOFF * 3);
+        assert_eq!(proxy.dialed().len(), 4);
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.1").await,
+            EndpointStatus::Offline { .. }
+        ));
+    }
+
+    fn online() -> EndpointStatus {
+        EndpointStatus::Online {
+            response_time_ms: 100,
+        }
+    }
+
+    fn degraded() -> EndpointStatus {
+        EndpointStatus::Degraded {
+            response_time_ms: 100,
+            reason: "slow".to_string(),
+        }
+    }
+
+    fn offline() -> EndpointStatus {
+        EndpointStatus::Offline {
+            error: "connection refused".to_string(),
+            kind: FailureKind::Connect,
+        }
+    }
+
+    /// A fresh endpoint and the context its checks would get under `monitoring`
+    fn endpoint_under(monitoring: MonitoringConfig) -> (EndpointInfo, CheckContext) {
+        let mut config = config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]);
+        config.monitoring = monitoring;
+        let scripted = Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang));
+        let monitor = MonitorService::with_dialers(config, scripted.clone(), scripted);
+        let context = monitor.check_context(&monitor.config.endpoints[0]);
+        let endpoint_info = monitor
+            .status_store
+            .try_write()
+            .unwrap()
+            .endpoints
+            .remove("192.0.2.1:5222")
+            .unwrap();
+        (endpoint_info, context)
+    }
+
+    /// The status shown after each of `results`
+    fn apply_results(
+        endpoint_info: &mut EndpointInfo,
+        context: &CheckContext,
+        results: Vec<EndpointStatus>,
+    ) -> Vec<EndpointStatus> {
+        results
+            .into_iter()
+            .map(|result| {
+                apply_result(endpoint_info, "192.0.2.1:5222", result, context, Utc::now());
+                endpoint_info.status.clone()
+            })
+            .collect()
+    }
+
+    fn thresholds(failures: u32, successes: u32) -> MonitoringConfig {
+        MonitoringConfig {
+            failures_before_offline: Some(failures),
+            successes_before_online: Some(successes),
+            ..create_default_config().monitoring
+        }
+    }
+
+    #[test]
+    fn failures_and_successes_must_repeat_before_status_changes() {
+        let (mut endpoint_info, context) = endpoint_under(thresholds(3, 2));
+        let statuses = apply_results(
+            &mut endpoint_info,
+            &context,
+            vec![
+                offline(),
+                offline(),
+                offline(),
+                online(),
+                offline(),
+                online(),
+                degraded(),
+                offline(),
+            ],
+        );
+
+        assert!(matches!(
+            statuses[0],
+            EndpointStatus::Failing {
+                failures: 1,
+                threshold: 3,
+                ..
+            }
+        ));
+        assert!(matches!(
+            statuses[1],
+            EndpointStatus::Failing { failures: 2, .. }
+        ));
+        assert!(matches!(statuses[2], EndpointStatus::Offline { .. }));
+        assert!(matches!(
+            statuses[3],
+            EndpointStatus::Recovering {
+                successes: 1,
+                threshold: 2,
+                ..
+            }
+        ));
+        // Once confirmed offline a single failure is enough to stay offline
+        assert!(matches!(statuses[4], EndpointStatus::Offline { .. }));
+        assert!(matches!(
+            statuses[5],
+            EndpointStatus::Recovering { successes: 1, .. }
+        ));
+        // Degraded responses count as successes
+        assert!(matches!(statuses[6], EndpointStatus::Degraded { .. }));
+        assert!(matches!(
+            statuses[7],
+            EndpointStatus::Failing { failures: 1, .. }
+        ));
+        assert!(!endpoint_info.confirmed_offline);
+    }
+
+    #[test]
+    fn inconclusive_checks_do_not_count_either_way() {
+        let (mut endpoint_info, context) = endpoint_under(thresholds(2, 1));
+        let unavailable = EndpointStatus::ProxyUnavailable {
+            reason: "proxy down".to_string(),
+        };
+        let statuses = apply_results(
+            &mut endpoint_info,
+            &context,
+            vec![offline(), unavailable, offline()],
+        );
+
+        assert!(matches!(
+            statuses[0],
+            EndpointStatus::Failing { failures: 1, .. }
+        ));
+        assert!(matches!(
+            statuses[1],
+            EndpointStatus::ProxyUnavailable { .. }
+        ));
+        assert!(matches!(statuses[2], EndpointStatus::Offline { .. }));
+    }
+
+    #[test]
+    fn default_thresholds_apply_results_directly() {
+        let (mut endpoint_info, context) = endpoint_under(create_default_config().monitoring);
+        let statuses = apply_results(&mut endpoint_info, &context, vec![offline(), online()]);
+
+        assert!(matches!(statuses[0], EndpointStatus::Offline { .. }));
+        assert!(matches!(statuses[1], EndpointStatus::Online { .. }));
+    }
+
+    #[test]
+    fn flapping_starts_at_the_limit_and_ends_with_the_window() {
+        let (mut endpoint_info, context) = endpoint_under(MonitoringConfig {
+            flapping_changes: Some(2),
+            flapping_window_seconds: Some(3600),
+            ..create_default_config().monitoring
+        });
+        let start = Utc::now();
+        let at = |minutes| start + TimeDelta::minutes(minutes);
+        let mut apply = |result, now| {
+            apply_result(&mut endpoint_info, "192.0.2.1:5222", result, &context, now);
+            endpoint_info.status.clone()
+        };
+
+        // The first result sets a status without changing one
+        assert!(matches!(
+            apply(offline(), at(0)),
+            EndpointStatus::Offline { .. }
+        ));
+        assert!(matches!(
+            apply(online(), at(1)),
+            EndpointStatus::Online { .. }
+        ));
+        assert!(matches!(
+            apply(offline(), at(2)),
+            EndpointStatus::Flapping { changes: 2, .. }
+        ));
+        // The change at minute 1 is still inside a window that starts exactly then
+        assert!(matches!(
+            apply(offline(), at(61)),
+            EndpointStatus::Flapping { changes: 2, .. }
+        ));
+        assert!(matches!(
+            apply(offline(), at(62)),
+            EndpointStatus::Offline { .. }
+        ));
+    }
+
+    #[test]
+    fn changes_below_the_limit_are_not_flapping() {
+        let (mut endpoint_info, context) = endpoint_under(MonitoringConfig {
+            flapping_changes: Some(3),
+            ..create_default_config().monitoring
+        });
+        let statuses = apply_results(
+            &mut endpoint_info,
+            &context,
+            vec![offline(), online(), offline(), offline(), online()],
+        );
+
+        assert!(matches!(statuses[2], EndpointStatus::Offline { .. }));
+        assert!(matches!(statuses[3], EndpointStatus::Offline { .. }));
+        assert!(matches!(
+            statuses[4],
+            EndpointStatus::Flapping { changes: 3, .. }
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_cycle_without_proxy_leaves_endpoints_unknown() {
+        let proxy = Arc::new(ScriptedDialer::new(ScriptedOutcome::Connect {
+            delay: Duration::ZERO,
+        }));
+        proxy.set_probe_failure(Some("connection refused".to_string()));
+        let monitor = MonitorService::with_dialers(
+            config(vec![endpoint("192.0.2.1", CheckMode::Proxy)]),
+            proxy.clone(),
+            Arc::new(ScriptedDialer::new(ScriptedOutcome::Hang)),
+        );
+
+        monitor.run_cycle().await;
+
+        assert!(matches!(
+            status_of(&monitor, "192.0.2.1").await,
+            EndpointStatus::ProxyUnavailable { .. }
+        ));
+        assert!(proxy.dialed().is_empty());
+    }
 }
@@ -0,0 +1,365 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+
+/// Number of base32 characters in a v3 onion address (without `.onion`)
+const V3_ADDRESS_LENGTH: usize = 56;
+
+/// Number of base32 characters in an obsolete v2 onion address (without `.onion`)
+const V2_ADDRESS_LENGTH: usize = 16;
+
+/// Version byte embedded in v3 onion addresses
+const V3_VERSION: u8 = 0x03;
+
+/// Prefix hashed together with the key and version to compute the address checksum
+const CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+
+/// Number of base32 characters in an x25519 client authorization key
+const CLIENT_AUTH_KEY_LENGTH: usize = 52;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum OnionAddressError {
+    #[error("not an onion address (missing .onion suffix)")]
+    MissingSuffix,
+    #[error("v2 onion addresses are obsolete and can no longer be reached over Tor")]
+    ObsoleteV2,
+    #[error("expected {V3_ADDRESS_LENGTH} characters before .onion, found {0}")]
+    InvalidLength(usize),
+    #[error("invalid base32 character {0:?}")]
+    InvalidCharacter(char),
+    #[error("unsupported onion address version {0}")]
+    UnsupportedVersion(u8),
+    #[error("checksum mismatch, the address probably contains a typo")]
+    ChecksumMismatch,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ClientAuthKeyError {
+    #[error("unsupported key type {0:?}, only x25519 keys are supported")]
+    UnsupportedKeyType(String),
+    #[error("expected {CLIENT_AUTH_KEY_LENGTH} base32 characters, found {0}")]
+    InvalidLength(usize),
+    #[error("invalid base32 character {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// A validated v3 onion service address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    public_key: [u8; 32],
+}
+
+impl OnionAddress {
+    /// Parse and validate a v3 onion address such as `<56 base32 chars>.onion`
+    ///
+    /// Checks the length, base32 alphabet, version byte and embedded checksum as described in
+    /// Tor's rend-spec-v3. Subdomains in front of the address are ignored.
+    pub fn parse(address: &str) -> Result<Self, OnionAddressError> {
+        let address = address.to_ascii_lowercase();
+        let host = address
+            .strip_suffix(".onion")
+            .ok_or(OnionAddressError::MissingSuffix)?;
+        let label = host.rsplit('.').next().unwrap_or(host);
+
+        match label.len() {
+            V3_ADDRESS_LENGTH => {}
+            V2_ADDRESS_LENGTH => return Err(OnionAddressError::ObsoleteV2),
+            length => return Err(OnionAddressError::InvalidLength(length)),
+        }
+
+        // 56 base32 characters decode to exactly 35 bytes: PUBKEY (32) | CHECKSUM (2) | VERSION (1)
+        let decoded = base32_decode(label)?;
+        let version = decoded[34];
+        if version != V3_VERSION {
+            return Err(OnionAddressError::UnsupportedVersion(version));
+        }
+
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&decoded[..32]);
+        if checksum(&public_key) != decoded[32..34] {
+            return Err(OnionAddressError::ChecksumMismatch);
+        }
+
+        Ok(Self { public_key })
+    }
+
+    /// The address of the service with the given ed25519 identity public key
+    pub fn from_public_key(public_key: [u8; 32]) -> Self {
+        Self { public_key }
+    }
+
+    /// The service's ed25519 identity public key
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// The address without the `.onion` suffix, as Tor's control port expects it
+    pub fn service_id(&self) -> String {
+        let address = self.to_string();
+        address.trim_end_matches(".onion").to_string()
+    }
+
+    /// Whether `address` claims to be an onion address and so should be validated as one
+    pub fn is_onion(address: &str) -> bool {
+        address.to_ascii_lowercase().ends_with(".onion")
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = OnionAddressError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        Self::parse(address)
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = Vec::with_capacity(35);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&checksum(&self.public_key));
+        bytes.push(V3_VERSION);
+        write!(f, "{}.onion", base32_encode(&bytes))
+    }
+}
+
+/// x25519 private key for an onion service that requires client authorization
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientAuthKey {
+    private_key: [u8; 32],
+}
+
+impl ClientAuthKey {
+    /// Parse a key in the format of Tor's `.auth_private` files
+    ///
+    /// Accepts the bare base32 key, `x25519:<key>` or a whole
+    /// `<address>:descriptor:x25519:<key>` line.
+    pub fn parse(key: &str) -> Result<Self, ClientAuthKeyError> {
+        let key = key.trim().to_ascii_lowercase();
+        let mut parts = key.rsplit(':');
+        let encoded = parts.next().unwrap_or_default();
+        if let Some(key_type) = parts.next()
+            && key_type != "x25519"
+        {
+            return Err(ClientAuthKeyError::UnsupportedKeyType(key_type.to_string()));
+        }
+
+        if encoded.len() != CLIENT_AUTH_KEY_LENGTH {
+            return Err(ClientAuthKeyError::InvalidLength(encoded.len()));
+        }
+        // 52 base32 characters carry 260 bits, the last 4 of which are padding
+        let decoded = base32_decode(encoded).map_err(|error| match error {
+            OnionAddressError::InvalidCharacter(c) => ClientAuthKeyError::InvalidCharacter(c),
+            _ => unreachable!("base32 decoding only fails on invalid characters"),
+        })?;
+
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&decoded[..32]);
+        Ok(Self { private_key })
+    }
+
+    /// The key in base64, as `ONION_CLIENT_AUTH_ADD` expects it
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.private_key)
+    }
+}
+
+impl FromStr for ClientAuthKey {
+    type Err = ClientAuthKeyError;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        Self::parse(key)
+    }
+}
+
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the private key out of logs
+        f.write_str("ClientAuthKey(..)")
+    }
+}
+
+/// CHECKSUM = H(".onion checksum" | PUBKEY | VERSION)[:2]
+fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(CHECKSUM_PREFIX);
+    hasher.update(public_key);
+    hasher.update([V3_VERSION]);
+    let digest = hasher.finalize();
+    [digest[0], digest[1]]
+}
+
+fn base32_decode(input: &str) -> Result<Vec<u8>, OnionAddressError> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&symbol| symbol as char == c)
+            .ok_or(OnionAddressError::InvalidCharacter(c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(output)
+}
+
+fn base32_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in input {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "g3hv4uynnmynqqq2mchf3fcm3yd46kfzmcdogejuckgwknwyq5ya6iad.onion";
+
+    fn decoded(address: &str) -> Vec<u8> {
+        base32_decode(address.trim_end_matches(".onion")).unwrap()
+    }
+
+    #[test]
+    fn valid_address_round_trips_to_its_public_key() {
+        let onion = OnionAddress::parse(ADDRESS).unwrap();
+        assert_eq!(onion.public_key()[..], decoded(ADDRESS)[..32]);
+        assert_eq!(onion.to_string(), ADDRESS);
+        assert_eq!(OnionAddress::from_public_key(*onion.public_key()), onion);
+        assert_eq!(
+            onion.service_id(),
+            "g3hv4uynnmynqqq2mchf3fcm3yd46kfzmcdogejuckgwknwyq5ya6iad"
+        );
+    }
+
+    #[test]
+    fn uppercase_and_subdomains_are_accepted() {
+        let onion = OnionAddress::parse(ADDRESS).unwrap();
+        assert_eq!(
+            OnionAddress::parse(&ADDRESS.to_ascii_uppercase()),
+            Ok(onion)
+        );
+        assert_eq!(OnionAddress::parse(&format!("www.{}", ADDRESS)), Ok(onion));
+    }
+
+    #[test]
+    fn typo_fails_the_checksum() {
+        let typo = ADDRESS.replacen("g3hv", "g3hw", 1);
+        assert_eq!(
+            OnionAddress::parse(&typo),
+            Err(OnionAddressError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn wrong_version_is_rejected() {
+        let mut bytes = decoded(ADDRESS);
+        bytes[34] = 0x02;
+        let address = format!("{}.onion", base32_encode(&bytes));
+        assert_eq!(
+            OnionAddress::parse(&address),
+            Err(OnionAddressError::UnsupportedVersion(0x02))
+        );
+    }
+
+    #[test]
+    fn v2_address_is_rejected() {
+        assert_eq!(
+            OnionAddress::parse("expyuzz4wqqyqhjn.onion"),
+            Err(OnionAddressError::ObsoleteV2)
+        );
+    }
+
+    #[test]
+    fn wrong_length_and_suffix_are_rejected() {
+        assert_eq!(
+            OnionAddress::parse("abc.onion"),
+            Err(OnionAddressError::InvalidLength(3))
+        );
+        assert_eq!(
+            OnionAddress::parse("example.com"),
+            Err(OnionAddressError::MissingSuffix)
+        );
+    }
+
+    #[test]
+    fn non_base32_characters_are_rejected() {
+        let address = ADDRESS.replacen('g', "1", 1);
+        assert_eq!(
+            OnionAddress::parse(&address),
+            Err(OnionAddressError::InvalidCharacter('1'))
+        );
+        assert_eq!(
+            base32_decode("abc8"),
+            Err(OnionAddressError::InvalidCharacter('8'))
+        );
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648_vectors() {
+        // RFC 4648 section 10, lowercase and without padding
+        for (plain, encoded) in [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ] {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn client_auth_key_accepts_auth_private_formats() {
+        let bare = "qkmfpxqmxhx7m3dsizb3pj6wgaiz24qdlbjc2zqsjmrffbtkpv2a";
+        let key = ClientAuthKey::parse(bare).unwrap();
+        assert_eq!(
+            ClientAuthKey::parse(&format!("x25519:{}", bare.to_ascii_uppercase())),
+            Ok(key.clone())
+        );
+        assert_eq!(
+            ClientAuthKey::parse(&format!(
+                "{}:descriptor:x25519:{}",
+                ADDRESS.trim_end_matches(".onion"),
+                bare
+            )),
+            Ok(key.clone())
+        );
+        assert_eq!(format!("{:?}", key), "ClientAuthKey(..)");
+        assert!(matches!(
+            ClientAuthKey::parse(&format!("ed25519:{}", bare)),
+            Err(ClientAuthKeyError::UnsupportedKeyType(_))
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+
+use crate::monitor::SharedStatusStore;
+use crate::tor_control::{TorControl, TorControlError};
+use crate::types::{OnionServiceConfig, TorControlConfig};
+
+/// Port the dashboard's onion service listens on unless configured otherwise
+const DEFAULT_PORT: u16 = 80;
+
+/// Wait before re-adding the service after the control connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Key type prefix `ADD_ONION` uses for v3 onion service keys
+const KEY_PREFIX: &str = "ED25519-V3:";
+
+#[derive(Error, Debug)]
+pub enum OnionServiceError {
+    #[error(transparent)]
+    Control(#[from] TorControlError),
+    #[error("Failed to access key file {path}: {source}")]
+    KeyFile {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Key file {0} does not hold an {KEY_PREFIX} key")]
+    InvalidKey(String),
+}
+
+/// Keeps the dashboard published as an onion service for as long as the server runs
+///
+/// The service is tied to the control connection, so Tor removes it if the server goes away.
+/// The connection is held open and the service added again whenever it drops, e.g. when Tor
+/// restarts. Its key is stored in `key_file` so the address stays the same; a key that couldn't
+/// be stored is kept in memory and reused, so at least the address stays the same until the
+/// server restarts.
+pub fn publish_dashboard(
+    tor_control: TorControlConfig,
+    onion_service: OnionServiceConfig,
+    dashboard_addr: SocketAddr,
+    status_store: SharedStatusStore,
+) {
+    let target = reachable_addr(dashboard_addr);
+    tokio::spawn(async move {
+        let mut unsaved_key = None;
+        loop {
+            if let Err(error) = publish_once(
+                &tor_control,
+                &onion_service,
+                target,
+                &status_store,
+                &mut unsaved_key,
+            )
+            .await
+            {
+                println!("🧅 Dashboard onion service interrupted: {}", error);
+            }
+            status_store.write().await.dashboard_onion = None;
+            sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+async fn publish_once(
+    tor_control: &TorControlConfig,
+    onion_service: &OnionServiceConfig,
+    target: SocketAddr,
+    status_store: &SharedStatusStore,
+    unsaved_key: &mut Option<String>,
+) -> Result<(), OnionServiceError> {
+    let private_key = match unsaved_key {
+        Some(key) => Some(key.clone()),
+        None => read_key(&onion_service.key_file).await?,
+    };
+    let mut control = TorControl::connect(tor_control).await?;
+    let added = control
+        .add_onion(
+            private_key.as_deref(),
+            onion_service.port.unwrap_or(DEFAULT_PORT),
+            target,
+        )
+        .await?;
+    if added.private_key.is_some() {
+        *unsaved_key = added.private_key;
+    }
+    if let Some(new_key) = unsaved_key.as_deref() {
+        // Tor already serves the address, so keep it up and try saving again on reconnect
+        match write_key(&onion_service.key_file, new_key).await {
+            Ok(()) => {
+                println!(
+                    "🔑 Saved dashboard onion service key to {}",
+                    onion_service.key_file.display()
+                );
+                *unsaved_key = None;
+            }
+            Err(error) => println!(
+                "⚠️  Dashboard onion address will change on restart, the key wasn't saved: {}",
+                error
+            ),
+        }
+    }
+
+    let address = format!("{}.onion", added.service_id);
+    println!("🧅 Dashboard published at http://{}/", address);
+    status_store.write().await.dashboard_onion = Some(address);
+
+    // Nothing is subscribed, so this only returns once the connection is gone
+    loop {
+        control.next_event().await?;
+    }
+}
+
+/// The stored key, or `None` when the service hasn't been created yet
+async fn read_key(path: &Path) -> Result<Option<String>, OnionServiceError> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(key_file_error(path, source)),
+    };
+    let key = contents.trim();
+    if !key.starts_with(KEY_PREFIX) {
+        return Err(OnionServiceError::InvalidKey(path.display().to_string()));
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// Store a newly generated key, readable by the owner only
+///
+/// The key is written next to the key file first and then moved into place, so an interrupted
+/// write can't leave a truncated key behind.
+async fn write_key(path: &Path, key: &str) -> Result<(), OnionServiceError> {
+    let temporary = PathBuf::from(format!("{}.tmp", path.display()));
+    // A leftover from an interrupted write may have other permissions, so start afresh
+    let _ = tokio::fs::remove_file(&temporary).await;
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options
+        .open(&temporary)
+        .await
+        .map_err(|source| key_file_error(&temporary, source))?;
+    file.write_all(format!("{}\n", key).as_bytes())
+        .await
+        .map_err(|source| key_file_error(&temporary, source))?;
+    file.sync_all()
+        .await
+        .map_err(|source| key_file_error(&temporary, source))?;
+    tokio::fs::rename(&temporary, path)
+        .await
+        .map_err(|source| key_file_error(path, source))
+}
+
+fn key_file_error(path: &Path, source: std::io::Error) -> OnionServiceError {
+    OnionServiceError::KeyFile {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Tor can't forward to a wildcard address, so point it at loopback instead
+fn reachable_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn written_key_reads_back() {
+        let path = std::env::temp_dir().join(format!("onion-{}.key", std::process::id()));
+        // Left behind by an earlier, interrupted write
+        tokio::fs::write(format!("{}.tmp", path.display()), "ED25519-V3:trunc")
+            .await
+            .unwrap();
+
+        write_key(&path, "ED25519-V3:c2VjcmV0").await.unwrap();
+        let key = read_key(&path).await;
+        #[cfg(unix)]
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(key.unwrap().as_deref(), Some("ED25519-V3:c2VjcmV0"));
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+    }
+}
@@ -1,13 +1,20 @@
-use axum::Router;
-use axum::extract::State;
+use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::Html;
 use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use tower::ServiceBuilder;
 
-use crate::monitor::{SharedStatusStore, start_background_monitoring};
-use crate::templates::dashboard_page;
-use crate::types::Config;
+use crate::history::uptime;
+use crate::monitor::{MonitorService, SharedStatusStore, start_background_monitoring};
+use crate::onion_service::publish_dashboard;
+use crate::templates::{dashboard_page, endpoint_page};
+use crate::types::{
+    CheckMode, Config, EndpointStatus, LatencyAnomaly, LatencyBaseline, LatencyStats, ProxyStatus,
+    ReferenceStatus, SchedulerStats, TorStatus, Uptime,
+};
 
 pub type AppState = SharedStatusStore;
 
@@ -15,9 +22,20 @@ pub async fn create_app(config: Config) -> Router {
     // Start background monitoring and get shared status store
     let status_store = start_background_monitoring(config).await;
 
+    router(status_store)
+}
+
+/// Create the app around an already configured monitor, e.g. one using a custom dialer
+pub fn create_app_with_monitor(monitor: MonitorService) -> Router {
+    router(monitor.spawn())
+}
+
+fn router(status_store: SharedStatusStore) -> Router {
     Router::new()
         .route("/", get(dashboard_handler))
+        .route("/endpoint/:key", get(endpoint_handler))
         .route("/health", get(health_handler))
+        .route("/api/endpoints", get(endpoints_handler))
         .with_state(status_store)
         .layer(ServiceBuilder::new())
 }
@@ -28,20 +46,176 @@ async fn dashboard_handler(State(state): State<AppState>) -> Result<Html<String>
     Ok(Html(html.into_string()))
 }
 
-async fn health_handler() -> &'static str {
-    "OK"
+async fn endpoint_handler(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Html<String>, StatusCode> {
+    let status_store = state.read().await;
+    let endpoint_info = status_store
+        .endpoints
+        .get(&key)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let html = endpoint_page(&key, endpoint_info, status_store.dashboard_onion.as_deref());
+    Ok(Html(html.into_string()))
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    /// `ok`, or `degraded` when no proxy is up, Tor reports a problem or every reference onion
+    /// is failing
+    status: &'static str,
+    proxies: Vec<ProxyStatus>,
+    tor: Option<TorStatus>,
+    references: Vec<ReferenceStatus>,
+    /// Cycle duration, overruns and late or skipped checks
+    scheduler: SchedulerStats,
+}
+
+async fn health_handler(State(state): State<AppState>) -> Json<HealthResponse> {
+    let status_store = state.read().await;
+    let proxies_down =
+        !status_store.proxies.is_empty() && status_store.proxies.iter().all(ProxyStatus::is_down);
+    let tor_problem = status_store
+        .tor
+        .as_ref()
+        .is_some_and(|tor| !tor.is_healthy());
+
+    Json(HealthResponse {
+        status: if proxies_down || tor_problem || status_store.local_problem() {
+            "degraded"
+        } else {
+            "ok"
+        },
+        proxies: status_store.proxies.clone(),
+        tor: status_store.tor.clone(),
+        references: status_store.references.clone(),
+        scheduler: status_store.scheduler.clone(),
+    })
+}
+
+#[derive(Serialize)]
+struct EndpointResponse {
+    key: String,
+    name: String,
+    address: String,
+    port: u16,
+    mode: CheckMode,
+    /// Outcome of the last completed check
+    status: EndpointStatus,
+    /// Whether a check is running right now
+    checking: bool,
+    last_check: Option<DateTime<Utc>>,
+    /// When the endpoint last went up or down
+    since: Option<DateTime<Utc>>,
+    /// Percentage of checks that found the endpoint up over the last 24h, 7d and 30d
+    uptime: Uptime,
+    /// Response time percentiles and histogram over the latency window
+    latency: Option<LatencyStats>,
+    /// Learned usual response time
+    baseline: Option<LatencyBaseline>,
+    /// Set when the last check was much slower than the baseline
+    anomaly: Option<LatencyAnomaly>,
+    /// Response time divided by that of the reference onions
+    relative_latency: Option<f64>,
+    /// The last check failed while the reference onions were failing too
+    local_problem: bool,
+    /// Current interval, while intervals adapt to each endpoint's status
+    adapted_interval_seconds: Option<u64>,
+}
+
+async fn endpoints_handler(State(state): State<AppState>) -> Json<Vec<EndpointResponse>> {
+    let status_store = state.read().await;
+    let now = Utc::now();
+    let mut endpoints: Vec<EndpointResponse> = status_store
+        .endpoints
+        .iter()
+        .map(|(key, endpoint_info)| EndpointResponse {
+            key: key.clone(),
+            name: endpoint_info.endpoint.name.clone(),
+            address: endpoint_info.endpoint.address.clone(),
+            port: endpoint_info.endpoint.port,
+            mode: endpoint_info.endpoint.mode,
+            status: endpoint_info.status.clone(),
+            checking: endpoint_info.checking,
+            last_check: endpoint_info.last_check,
+            since: endpoint_info.since,
+            uptime: uptime(&endpoint_info.samples, now),
+            latency: endpoint_info.latency.clone(),
+            baseline: endpoint_info.baseline,
+            anomaly: endpoint_info.anomaly,
+            relative_latency: endpoint_info.relative_latency,
+            local_problem: endpoint_info.local_problem,
+            adapted_interval_seconds: endpoint_info
+                .adapted_interval
+                .map(|interval| interval.as_secs()),
+        })
+        .collect();
+    endpoints.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Json(endpoints)
 }
 
 pub async fn run_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let app = create_app(config.clone()).await;
+    let monitor = MonitorService::new(config.clone()).start();
+    let status_store = monitor.status_store();
 
     let addr = format!("{}:{}", config.server.host, config.server.port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
 
+    if let (Some(onion_service), Some(tor_control)) =
+        (&config.server.onion_service, &config.tor_control)
+    {
+        publish_dashboard(
+            tor_control.clone(),
+            onion_service.clone(),
+            listener.local_addr()?,
+            status_store.clone(),
+        );
+    }
+
+    let app = router(status_store);
+
     println!("🚀 Server running on http://{}", addr);
     println!("📊 Dashboard available at http://{}/", addr);
 
-    axum::serve(listener, app).await?;
+    // Monitoring winds down as soon as the signal arrives, alongside the server closing its
+    // connections rather than after the last one is gone
+    let served = axum::serve(listener, app)
+        .with_graceful_shutdown(monitor.stop_on(shutdown_signal()))
+        .await;
+    monitor.shutdown().await;
+    served?;
 
     Ok(())
 }
+
+/// Resolve on Ctrl-C or, on Unix, SIGTERM as sent by systemd
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            println!("⚠️  Failed to listen for Ctrl-C: {}", error);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                println!("⚠️  Failed to listen for SIGTERM: {}", error);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    println!("🛑 Shutting down, no new checks will be started");
+}
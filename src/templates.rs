@@ -1,116 +1,387 @@
-use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+
+use chrono::{DateTime, TimeDelta, Utc};
 use maud::{DOCTYPE, Markup, PreEscaped, html};
 
-use crate::types::{EndpointInfo, EndpointStatus, StatusStore};
+use crate::history::uptime;
+use crate::types::{
+    CheckMode, CheckRecord, CheckTimings, CircuitRecord, DescriptorDiagnostics, Endpoint,
+    EndpointInfo, EndpointStatus, HsDirResult, LatencyStats, ProxyHealth, ProxyStatus,
+    ReferenceStatus, Relay, SchedulerStats, StatusStore, TorStatus, Transition, VantageResult,
+};
 
 const CSS_STYLES: &str = include_str!("../assets/styles.css");
 const JAVASCRIPT: &str = include_str!("../assets/app.js");
 
+/// How long an overrun, late or skipped check keeps the scheduler banner in its warning state
+const SCHEDULER_WARNING: TimeDelta = TimeDelta::hours(1);
+
 pub fn dashboard_page(status_store: &StatusStore) -> Markup {
-    html! {
-        (DOCTYPE)
-        html lang="en" {
-            head {
-                meta charset="utf-8";
-                meta name="viewport" content="width=device-width, initial-scale=1";
-                title { "Tor Endpoint Monitor" }
-                style { (PreEscaped(CSS_STYLES)) }
-                script { (PreEscaped(JAVASCRIPT)) }
+    layout(
+        "Tor Endpoint Monitor",
+        status_store.dashboard_onion.as_deref(),
+        html! {
+            header class="header" {
+                h1 class="title" {
+                    span class="title-icon" { "🔗" }
+                    "TOR ENDPOINT MONITOR"
+                }
+                div class="status-summary" {
+                    (status_summary(status_store))
+                }
             }
-            body {
-                div class="container" {
-                    header class="header" {
-                        h1 class="title" {
-                            span class="title-icon" { "🔗" }
-                            "TOR ENDPOINT MONITOR"
+
+            @if !status_store.proxies.is_empty() {
+                (proxy_banner(&status_store.proxies))
+            }
+
+            @if let Some(tor) = &status_store.tor {
+                (tor_banner(tor))
+            }
+
+            @if !status_store.references.is_empty() {
+                (reference_banner(status_store))
+            }
+
+            (shared_relays_banner(status_store))
+
+            @if status_store.scheduler.cycles > 0 {
+                (scheduler_banner(&status_store.scheduler))
+            }
+
+            main class="main-content" {
+                @if status_store.endpoints.is_empty() {
+                    div class="no-endpoints" {
+                        p { "No endpoints configured" }
+                        p class="help-text" { "Add endpoints to config.toml to start monitoring" }
+                    }
+                } @else {
+                    div class="table-container" {
+                        table class="endpoints-table" {
+                            thead {
+                                tr {
+                                    th { "Status" }
+                                    th { "Name" }
+                                    th { "Address" }
+                                    th { "Port" }
+                                    th { "Response Time" }
+                                    th title="Share of checks in the last 24 hours that found the endpoint up" { "24h" }
+                                    th title="Share of checks in the last 7 days that found the endpoint up" { "7d" }
+                                    th title="Share of checks in the last 30 days that found the endpoint up" { "30d" }
+                                    th { "Last Check" }
+                                    th { "Details" }
+                                }
+                            }
+                            tbody {
+                                @for endpoint_info in sorted_endpoints(status_store) {
+                                    (endpoint_row(endpoint_info))
+                                }
+                            }
                         }
-                        div class="status-summary" {
-                            (status_summary(status_store))
+                    }
+                }
+            }
+        },
+    )
+}
+
+pub fn endpoint_page(
+    key: &str,
+    endpoint_info: &EndpointInfo,
+    dashboard_onion: Option<&str>,
+) -> Markup {
+    let endpoint = &endpoint_info.endpoint;
+    let status = &endpoint_info.status;
+    let uptime = uptime(&endpoint_info.samples, Utc::now());
+
+    layout(
+        &format!("{} - Tor Endpoint Monitor", endpoint.name),
+        dashboard_onion,
+        html! {
+            header class="header" {
+                a href="/" class="back-link" { "← All endpoints" }
+                h1 class="title" {
+                    span class="status-emoji" { (status.status_emoji()) }
+                    (endpoint.name)
+                }
+            }
+
+            main class="main-content" {
+                section class="detail-section" {
+                    h2 class="section-title" { "Endpoint" }
+                    dl class="detail-list" {
+                        dt { "Address" }
+                        dd {
+                            code class="address-value clickable-address" data-address=(endpoint.address) title="Click to copy address" { (endpoint.address) }
+                        }
+                        dt { "Port" }
+                        dd { (endpoint.port) }
+                        @if let Some(onion_address) = endpoint.onion_address() {
+                            dt { "Public Key" }
+                            dd { code class="public-key" { (hex(onion_address.public_key())) } }
+                        }
+                        @if let Some(interval) = endpoint_info.adapted_interval {
+                            dt { "Check Interval" }
+                            dd { "Every " (interval.as_secs()) "s (adapted to its status)" }
+                        } @else if let Some(seconds) = endpoint.check_interval_seconds {
+                            dt { "Check Interval" }
+                            dd { "Every " (seconds) "s" }
+                        }
+                        @if let Some(seconds) = endpoint.connection_timeout_seconds {
+                            dt { "Timeout" }
+                            dd { (seconds) "s" }
+                        }
+                        @if endpoint.client_auth_key.is_some() {
+                            dt { "Client Auth" }
+                            dd { "Key registered with Tor each cycle" }
+                        }
+                        dt { "Key" }
+                        dd { code { (key) } }
+                        dt { "Status" }
+                        dd class=(status.css_class()) {
+                            span class="status-text" { (status.status_text()) }
+                            @if endpoint_info.checking {
+                                span class="spinner" title="Checking now" {}
+                            }
+                        }
+                        @if let (Some(text), Some(since)) = (since_text(endpoint_info), &endpoint_info.since) {
+                            dt { "Since" }
+                            dd {
+                                (text) " "
+                                span class="timestamp" { "(" (format_timestamp(since)) ")" }
+                            }
+                        }
+                        @match status {
+                            EndpointStatus::Degraded { reason, .. } => {
+                                dt { "Degraded" }
+                                dd { (reason) }
+                            }
+                            EndpointStatus::Flapping { changes, window_seconds, last } => {
+                                dt { "Flapping" }
+                                dd {
+                                    (changes) " up/down changes in the last " (format_duration(*window_seconds))
+                                    ", last check " (last.status_text())
+                                }
+                            }
+                            _ => {}
+                        }
+                        @if let Some(relative_latency) = endpoint_info.relative_latency {
+                            dt { "Relative to Reference" }
+                            dd { (format!("×{:.2}", relative_latency)) " the reference onions' response time" }
+                        }
+                        @if endpoint_info.local_problem {
+                            dt { "Local Problem" }
+                            dd class="local-problem" { "⚠️ The reference onions were failing too when the last check completed" }
+                        }
+                        @if let Some(baseline) = &endpoint_info.baseline {
+                            dt { "Usual Response Time" }
+                            dd {
+                                "~" (baseline.mean_ms.round()) "ms ± " (baseline.deviation_ms.round()) "ms"
+                                @if let Some(anomaly) = &endpoint_info.anomaly {
+                                    span class="slower-than-usual" { " 🐢 last check took " (anomaly.response_time_ms) "ms" }
+                                }
+                            }
+                        }
+                        dt { "Uptime" }
+                        dd class="uptime-list" {
+                            @for (label, percent) in [("24h", uptime.day), ("7d", uptime.week), ("30d", uptime.month)] {
+                                span class="uptime-window" { (label) " " (uptime_value(percent)) }
+                            }
                         }
+                        dt { "Last Check" }
+                        dd {
+                            @if let Some(last_check) = &endpoint_info.last_check {
+                                span class="timestamp" { (format_timestamp(last_check)) }
+                            } @else {
+                                span class="no-data" { "Never" }
+                            }
+                        }
+                        @if let Some((kind, error)) = status.failure() {
+                            dt { "Error" }
+                            dd {
+                                span class="failure-kind" { (kind.label()) }
+                                span class="error-message" { (error) }
+                            }
+                        }
+                    }
+                }
+
+                section class="detail-section" {
+                    h2 class="section-title" { "Latency Breakdown" }
+                    @if let Some(timings) = &endpoint_info.last_timings {
+                        (timings_table(timings, endpoint.mode))
+                    } @else {
+                        p class="no-data" { "No completed checks yet" }
                     }
+                }
+
+                @if let Some(latency) = &endpoint_info.latency {
+                    (latency_section(latency))
+                }
 
-                    main class="main-content" {
-                        @if status_store.is_empty() {
-                            div class="no-endpoints" {
-                                p { "No endpoints configured" }
-                                p class="help-text" { "Add endpoints to config.toml to start monitoring" }
+                @if let Some(descriptor) = &endpoint_info.descriptor {
+                    (descriptor_section(descriptor))
+                }
+
+                @if !endpoint_info.history.is_empty() {
+                    (history_section(&endpoint_info.history))
+                }
+
+                @if !endpoint_info.transitions.is_empty() {
+                    (transitions_section(&endpoint_info.transitions))
+                }
+
+                @if !endpoint_info.vantages.is_empty() {
+                    section class="detail-section" {
+                        h2 class="section-title" { "Per-Proxy Results" }
+                        table class="endpoints-table timings-table" {
+                            thead {
+                                tr {
+                                    th { "Proxy" }
+                                    th { "Status" }
+                                    th { "Response Time" }
+                                    th { "Details" }
+                                }
                             }
-                        } @else {
-                            div class="table-container" {
-                                table class="endpoints-table" {
-                                    thead {
-                                        tr {
-                                            th { "Status" }
-                                            th { "Name" }
-                                            th { "Address" }
-                                            th { "Port" }
-                                            th { "Response Time" }
-                                            th { "Last Check" }
-                                            th { "Details" }
+                            tbody {
+                                @for vantage in &endpoint_info.vantages {
+                                    tr class=(vantage.status.css_class()) {
+                                        td class="name-cell" { (vantage.proxy) }
+                                        td class="status-cell" {
+                                            span class="status-indicator" {
+                                                span class="status-emoji" { (vantage.status.status_emoji()) }
+                                                span class="status-text" { (vantage.status.status_text()) }
+                                            }
                                         }
-                                    }
-                                    tbody {
-                                        @for endpoint_info in sorted_endpoints(status_store) {
-                                            (endpoint_row(endpoint_info))
+                                        td {
+                                            @if let Some(response_time_ms) = vantage.status.response_time_ms() {
+                                                span class="response-time" { (response_time_ms) "ms" }
+                                            } @else {
+                                                span class="no-data" { "—" }
+                                            }
+                                        }
+                                        td {
+                                            @if let Some((kind, error)) = vantage.status.failure() {
+                                                span class="failure-kind" { (kind.label()) }
+                                                span class="error-message" { (error) }
+                                            } @else {
+                                                span class="no-data" { "—" }
+                                            }
                                         }
                                     }
                                 }
                             }
                         }
                     }
+                }
+            }
+        },
+    )
+}
 
-                    footer class="footer" {
-                        div class="footer-content" {
-                            div class="footer-left" {
-                                span class="last-update" { "Last updated: " (format_timestamp(&Utc::now())) }
-                                span class="auto-refresh" { "Auto-refresh: 30s" }
-                            }
-                            div class="footer-right" {
-                                span class="made-in" {
-                                    "Made in "
-                                    svg class="usa-flag" viewBox="0 0 120 63" width="24" height="13" fill="none" xmlns="http://www.w3.org/2000/svg" {
-                                        // Red and white stripes
-                                        rect y="0" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="4.846" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="9.692" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="14.538" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="19.384" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="24.23" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="29.076" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="33.922" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="38.768" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="43.614" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="48.46" width="120" height="4.846" fill="#BD3D44" {}
-                                        rect y="53.306" width="120" height="4.846" fill="#FFF" {}
-                                        rect y="58.152" width="120" height="4.846" fill="#BD3D44" {}
-                                        // Blue field with stars
-                                        rect width="48" height="28" fill="#192F5D" {}
-                                        // Stars
-                                        g fill="#FFF" font-family="Arial" font-size="4" font-weight="bold" letter-spacing="0" {
-                                            text y="8" x="4" { "★" }
-                                            text y="8" x="12" { "★" }
-                                            text y="8" x="20" { "★" }
-                                            text y="8" x="28" { "★" }
-                                            text y="8" x="36" { "★" }
-                                            text y="14" x="8" { "★" }
-                                            text y="14" x="16" { "★" }
-                                            text y="14" x="24" { "★" }
-                                            text y="14" x="32" { "★" }
-                                            text y="14" x="40" { "★" }
-                                            text y="20" x="4" { "★" }
-                                            text y="20" x="12" { "★" }
-                                            text y="20" x="20" { "★" }
-                                            text y="20" x="28" { "★" }
-                                            text y="20" x="36" { "★" }
-                                            text y="26" x="8" { "★" }
-                                            text y="26" x="16" { "★" }
-                                            text y="26" x="24" { "★" }
-                                            text y="26" x="32" { "★" }
-                                            text y="26" x="40" { "★" }
-                                        }
+fn latency_section(latency: &LatencyStats) -> Markup {
+    let largest = latency
+        .histogram
+        .iter()
+        .map(|bucket| bucket.count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut lower_ms = 0;
+    let buckets: Vec<(String, usize)> = latency
+        .histogram
+        .iter()
+        .map(|bucket| {
+            let label = match bucket.upper_ms {
+                Some(upper_ms) => format!("{}–{}ms", lower_ms, upper_ms),
+                None => format!("over {}ms", lower_ms),
+            };
+            lower_ms = bucket.upper_ms.unwrap_or(lower_ms);
+            (label, bucket.count)
+        })
+        .collect();
+
+    html! {
+        section class="detail-section" {
+            h2 class="section-title" {
+                "Response Times (last " (format_duration(latency.window_seconds)) ")"
+            }
+            dl class="detail-list" {
+                dt { "Checks" }
+                dd { (latency.count) }
+                dt { "Median (p50)" }
+                dd { span class="response-time" { (latency.p50_ms) "ms" } }
+                dt { "p90" }
+                dd { span class="response-time" { (latency.p90_ms) "ms" } }
+                dt { "p99" }
+                dd { span class="response-time" { (latency.p99_ms) "ms" } }
+                dt { "Range" }
+                dd { (latency.min_ms) "ms – " (latency.max_ms) "ms" }
+            }
+            table class="endpoints-table timings-table histogram" {
+                thead {
+                    tr {
+                        th { "Response Time" }
+                        th { "Checks" }
+                        th {}
+                    }
+                }
+                tbody {
+                    @for (label, count) in &buckets {
+                        tr {
+                            td { (label) }
+                            td { (count) }
+                            td class="histogram-cell" {
+                                div class="histogram-bar" style=(format!("width: {}%", count * 100 / largest)) {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn descriptor_section(descriptor: &DescriptorDiagnostics) -> Markup {
+    html! {
+        section class="detail-section" {
+            h2 class="section-title" { "Descriptor Fetch" }
+            p class="descriptor-summary" { (descriptor.summary()) }
+            dl class="detail-list" {
+                dt { "Fetched At" }
+                dd { span class="timestamp" { (format_timestamp(&descriptor.checked_at)) } }
+                dt { "Introduction Points" }
+                dd {
+                    @if let Some(count) = descriptor.introduction_points {
+                        (count)
+                    } @else {
+                        span class="no-data" { "—" }
+                    }
+                }
+                @if let Some(error) = &descriptor.error {
+                    dt { "Error" }
+                    dd { span class="error-message" { (error) } }
+                }
+            }
+            @if !descriptor.hsdirs.is_empty() {
+                table class="endpoints-table timings-table" {
+                    thead {
+                        tr {
+                            th { "HSDir" }
+                            th { "Result" }
+                        }
+                    }
+                    tbody {
+                        @for attempt in &descriptor.hsdirs {
+                            tr {
+                                td { code { (attempt.hsdir) } }
+                                td {
+                                    @match &attempt.result {
+                                        HsDirResult::Requested => span class="no-data" { "No answer" },
+                                        HsDirResult::Received => span class="status-online" { "Received" },
+                                        HsDirResult::Failed { reason } => span class="status-offline" { "Failed: " (reason) },
                                     }
-                                    " by "
-                                    a href="https://github.com/w3irdrobot" target="_blank" rel="noopener noreferrer" class="author-link" { "w3irdrobot" }
                                 }
                             }
                         }
@@ -121,18 +392,319 @@ pub fn dashboard_page(status_store: &StatusStore) -> Markup {
     }
 }
 
+/// Number of checks listed on an endpoint's detail page
+const RECENT_CHECKS: usize = 20;
+
+fn history_section(history: &VecDeque<CheckRecord>) -> Markup {
+    html! {
+        section class="detail-section" {
+            h2 class="section-title" { "Recent Checks" }
+            table class="endpoints-table timings-table" {
+                thead {
+                    tr {
+                        th { "Time" }
+                        th { "Status" }
+                        th { "Response Time" }
+                        th { "Circuits" }
+                    }
+                }
+                tbody {
+                    @for record in history.iter().rev().take(RECENT_CHECKS) {
+                        tr class=(record.status.css_class()) {
+                            td { span class="timestamp" { (format_timestamp(&record.checked_at)) } }
+                            td class="status-cell" {
+                                span class="status-indicator" {
+                                    span class="status-emoji" { (record.status.status_emoji()) }
+                                    span class="status-text" { (record.status.status_text()) }
+                                }
+                                @if record.local_problem {
+                                    span class="local-problem" title="The reference onions were failing too" { " ⚠️" }
+                                }
+                            }
+                            td {
+                                @if let Some(response_time_ms) = record.status.response_time_ms() {
+                                    span class="response-time" { (response_time_ms) "ms" }
+                                } @else {
+                                    span class="no-data" { "—" }
+                                }
+                            }
+                            td {
+                                @if record.circuits.is_empty() {
+                                    span class="no-data" { "—" }
+                                }
+                                @for circuit in &record.circuits {
+                                    (circuit_path(circuit))
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn transitions_section(transitions: &VecDeque<Transition>) -> Markup {
+    html! {
+        section class="detail-section" {
+            h2 class="section-title" { "Status Changes" }
+            table class="endpoints-table timings-table" {
+                thead {
+                    tr {
+                        th { "Time" }
+                        th { "From" }
+                        th { "To" }
+                    }
+                }
+                tbody {
+                    @for transition in transitions.iter().rev() {
+                        tr class=[transition.up_down.then_some("up-down")] {
+                            td class="time-cell" { span class="timestamp" { (format_timestamp(&transition.at)) } }
+                            td { (transition.from) }
+                            td { (transition.to) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn circuit_path(circuit: &CircuitRecord) -> Markup {
+    html! {
+        div class="circuit" title={ "Circuit " (circuit.id) " (" (circuit.status) ")" } {
+            @if let Some(purpose) = &circuit.purpose {
+                span class="circuit-purpose" { (purpose) }
+            }
+            @for (position, relay) in circuit.path.iter().enumerate() {
+                @if position > 0 {
+                    " → "
+                }
+                span class="relay" title=(relay.fingerprint) { (relay.label()) }
+            }
+        }
+    }
+}
+
+/// A relay on the circuits of several endpoints that are currently failing
+struct SharedRelay<'a> {
+    relay: &'a Relay,
+    failing: Vec<&'a str>,
+    /// Online endpoints whose last check went through the relay too
+    working: usize,
+}
+
+/// Relays that carried the last check of more than one failing endpoint
+fn shared_relays(status_store: &StatusStore) -> Vec<SharedRelay<'_>> {
+    let mut relays: Vec<SharedRelay> = Vec::new();
+
+    for endpoint_info in sorted_endpoints(status_store) {
+        let Some(record) = endpoint_info.history.back() else {
+            continue;
+        };
+        let failing = match record.status.is_up() {
+            Some(up) => !up,
+            None => continue,
+        };
+
+        let mut used: Vec<&Relay> = record
+            .circuits
+            .iter()
+            .flat_map(|circuit| &circuit.path)
+            .collect();
+        used.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        used.dedup();
+
+        for relay in used {
+            let position = relays.iter().position(|shared| shared.relay == relay);
+            let shared = match position {
+                Some(position) => &mut relays[position],
+                None => {
+                    relays.push(SharedRelay {
+                        relay,
+                        failing: Vec::new(),
+                        working: 0,
+                    });
+                    relays.last_mut().unwrap()
+                }
+            };
+            if failing {
+                shared.failing.push(&endpoint_info.endpoint.name);
+            } else {
+                shared.working += 1;
+            }
+        }
+    }
+
+    relays.retain(|shared| shared.failing.len() > 1);
+    relays.sort_by_key(|shared| Reverse(shared.failing.len()));
+    relays
+}
+
+fn shared_relays_banner(status_store: &StatusStore) -> Markup {
+    let relays = shared_relays(status_store);
+
+    html! {
+        @if !relays.is_empty() {
+            div class="proxy-banner proxy-banner-degraded" {
+                span class="proxy-banner-title" { "⚠️ Failing endpoints share relays" }
+                div class="proxy-list" {
+                    @for shared in &relays {
+                        span class="proxy-item" title=(shared.relay.fingerprint) {
+                            (shared.relay.label()) ": " (shared.failing.join(", "))
+                            @if shared.working > 0 {
+                                " (also used by " (shared.working) " working)"
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn layout(title: &str, dashboard_onion: Option<&str>, content: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) }
+                style { (PreEscaped(CSS_STYLES)) }
+                script { (PreEscaped(JAVASCRIPT)) }
+            }
+            body {
+                div class="container" {
+                    (content)
+
+                    (footer(dashboard_onion))
+                }
+            }
+        }
+    }
+}
+
+fn footer(dashboard_onion: Option<&str>) -> Markup {
+    html! {
+    footer class="footer" {
+        div class="footer-content" {
+            div class="footer-left" {
+                span class="last-update" { "Last updated: " (format_timestamp(&Utc::now())) }
+                span class="auto-refresh" { "Auto-refresh: 30s" }
+                @if let Some(onion) = dashboard_onion {
+                    span class="dashboard-onion" {
+                        "Also on Tor: "
+                        code class="clickable-address" data-address=(onion) title="Click to copy address" { (onion) }
+                    }
+                }
+            }
+            div class="footer-right" {
+                span class="made-in" {
+                    "Made in "
+                    svg class="usa-flag" viewBox="0 0 120 63" width="24" height="13" fill="none" xmlns="http://www.w3.org/2000/svg" {
+                        // Red and white stripes
+                        rect y="0" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="4.846" width="120" height="4.846" fill="#FFF" {}
+                        rect y="9.692" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="14.538" width="120" height="4.846" fill="#FFF" {}
+                        rect y="19.384" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="24.23" width="120" height="4.846" fill="#FFF" {}
+                        rect y="29.076" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="33.922" width="120" height="4.846" fill="#FFF" {}
+                        rect y="38.768" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="43.614" width="120" height="4.846" fill="#FFF" {}
+                        rect y="48.46" width="120" height="4.846" fill="#BD3D44" {}
+                        rect y="53.306" width="120" height="4.846" fill="#FFF" {}
+                        rect y="58.152" width="120" height="4.846" fill="#BD3D44" {}
+                        // Blue field with stars
+                        rect width="48" height="28" fill="#192F5D" {}
+                        // Stars
+                        g fill="#FFF" font-family="Arial" font-size="4" font-weight="bold" letter-spacing="0" {
+                            text y="8" x="4" { "★" }
+                            text y="8" x="12" { "★" }
+                            text y="8" x="20" { "★" }
+                            text y="8" x="28" { "★" }
+                            text y="8" x="36" { "★" }
+                            text y="14" x="8" { "★" }
+                            text y="14" x="16" { "★" }
+                            text y="14" x="24" { "★" }
+                            text y="14" x="32" { "★" }
+                            text y="14" x="40" { "★" }
+                            text y="20" x="4" { "★" }
+                            text y="20" x="12" { "★" }
+                            text y="20" x="20" { "★" }
+                            text y="20" x="28" { "★" }
+                            text y="20" x="36" { "★" }
+                            text y="26" x="8" { "★" }
+                            text y="26" x="16" { "★" }
+                            text y="26" x="24" { "★" }
+                            text y="26" x="32" { "★" }
+                            text y="26" x="40" { "★" }
+                        }
+                    }
+                    " by "
+                    a href="https://github.com/w3irdrobot" target="_blank" rel="noopener noreferrer" class="author-link" { "w3irdrobot" }
+                }
+            }
+        }
+    }
+    }
+}
+
+fn timings_table(timings: &CheckTimings, mode: CheckMode) -> Markup {
+    let connect_label = match mode {
+        CheckMode::Proxy => "Proxy connect",
+        CheckMode::Direct => "TCP connect",
+    };
+    let phases = [
+        ("DNS resolve", timings.resolve_ms),
+        (connect_label, timings.tcp_connect_ms),
+        ("Auth negotiation", timings.auth_ms),
+        ("CONNECT reply", timings.connect_reply_ms),
+        ("Application handshake", timings.handshake_ms),
+        ("First byte", timings.first_byte_ms),
+    ];
+
+    html! {
+        table class="endpoints-table timings-table" {
+            thead {
+                tr {
+                    th { "Phase" }
+                    th { "Duration" }
+                }
+            }
+            tbody {
+                @for (phase, duration) in phases {
+                    tr {
+                        td class="name-cell" { (phase) }
+                        td {
+                            @if let Some(duration_ms) = duration {
+                                span class="response-time" { (duration_ms) "ms" }
+                            } @else {
+                                span class="no-data" { "—" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn sorted_endpoints(status_store: &StatusStore) -> Vec<&EndpointInfo> {
-    let mut endpoints: Vec<&EndpointInfo> = status_store.values().collect();
+    let mut endpoints: Vec<&EndpointInfo> = status_store.endpoints.values().collect();
 
-    // Sort by status priority: Online -> Checking -> Unknown -> Offline
-    // Within same status, sort by name for consistency
+    // Sort by status priority: Online -> Degraded/Recovering -> Unknown -> Failing/Flapping -> Offline
+    // Within same status, sort by name and then key, so rows only move when a result changes
     endpoints.sort_by(|a, b| {
         let status_priority = |status: &EndpointStatus| -> u8 {
             match status {
                 EndpointStatus::Online { .. } => 0,
-                EndpointStatus::Checking => 1,
-                EndpointStatus::Unknown => 2,
-                EndpointStatus::Offline { .. } => 3,
+                EndpointStatus::Degraded { .. } | EndpointStatus::Recovering { .. } => 1,
+                EndpointStatus::Unknown | EndpointStatus::ProxyUnavailable { .. } => 2,
+                EndpointStatus::Failing { .. } | EndpointStatus::Flapping { .. } => 3,
+                EndpointStatus::Offline { .. } => 4,
             }
         };
 
@@ -143,7 +715,10 @@ fn sorted_endpoints(status_store: &StatusStore) -> Vec<&EndpointInfo> {
         match a_priority.cmp(&b_priority) {
             std::cmp::Ordering::Equal => {
                 // If same status, sort by name
-                a.endpoint.name.cmp(&b.endpoint.name)
+                a.endpoint
+                    .name
+                    .cmp(&b.endpoint.name)
+                    .then_with(|| endpoint_key(&a.endpoint).cmp(&endpoint_key(&b.endpoint)))
             }
             other => other,
         }
@@ -155,15 +730,22 @@ fn sorted_endpoints(status_store: &StatusStore) -> Vec<&EndpointInfo> {
 fn status_summary(status_store: &StatusStore) -> Markup {
     let mut online = 0;
     let mut offline = 0;
+    let mut unstable = 0;
     let mut checking = 0;
     let mut unknown = 0;
 
-    for endpoint_info in status_store.values() {
+    for endpoint_info in status_store.endpoints.values() {
+        if endpoint_info.checking {
+            checking += 1;
+        }
         match &endpoint_info.status {
             EndpointStatus::Online { .. } => online += 1,
             EndpointStatus::Offline { .. } => offline += 1,
-            EndpointStatus::Checking => checking += 1,
-            EndpointStatus::Unknown => unknown += 1,
+            EndpointStatus::Degraded { .. }
+            | EndpointStatus::Flapping { .. }
+            | EndpointStatus::Failing { .. }
+            | EndpointStatus::Recovering { .. } => unstable += 1,
+            EndpointStatus::Unknown | EndpointStatus::ProxyUnavailable { .. } => unknown += 1,
         }
     }
 
@@ -177,6 +759,10 @@ fn status_summary(status_store: &StatusStore) -> Markup {
                 span class="stat-number" { (offline) }
                 span class="stat-label" { "Offline" }
             }
+            div class="stat unstable" {
+                span class="stat-number" { (unstable) }
+                span class="stat-label" { "Unstable" }
+            }
             div class="stat checking" {
                 span class="stat-number" { (checking) }
                 span class="stat-label" { "Checking" }
@@ -189,9 +775,198 @@ fn status_summary(status_store: &StatusStore) -> Markup {
     }
 }
 
+fn proxy_banner(proxies: &[ProxyStatus]) -> Markup {
+    let down = proxies.iter().filter(|proxy| proxy.is_down()).count();
+    let banner_class = if down == 0 {
+        "proxy-banner"
+    } else if down == proxies.len() {
+        "proxy-banner proxy-banner-down"
+    } else {
+        "proxy-banner proxy-banner-degraded"
+    };
+
+    html! {
+        div class=(banner_class) {
+            span class="proxy-banner-title" {
+                @if down == 0 {
+                    "🧅 Proxy OK"
+                } @else if down == proxies.len() {
+                    "⚠️ No proxy reachable: endpoint results are unknown until a proxy is back"
+                } @else {
+                    "⚠️ Some proxies are down"
+                }
+            }
+            div class="proxy-list" {
+                @for proxy in proxies {
+                    @match &proxy.health {
+                        ProxyHealth::Unknown => {
+                            span class="proxy-item status-unknown" { "⚪ " (proxy.name) }
+                        }
+                        ProxyHealth::Up { response_time_ms } => {
+                            span class="proxy-item status-online" title={ (response_time_ms) "ms" } { "🟢 " (proxy.name) }
+                        }
+                        ProxyHealth::Down { error } => {
+                            span class="proxy-item status-offline" title=(error) { "🔴 " (proxy.name) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn reference_banner(status_store: &StatusStore) -> Markup {
+    let references = &status_store.references;
+    let down = references
+        .iter()
+        .filter(|reference| reference.status.is_up() == Some(false))
+        .count();
+    let banner_class = if down == 0 {
+        "proxy-banner"
+    } else if down == references.len() {
+        "proxy-banner proxy-banner-down"
+    } else {
+        "proxy-banner proxy-banner-degraded"
+    };
+
+    html! {
+        div class=(banner_class) {
+            span class="proxy-banner-title" {
+                @if down == 0 {
+                    "🧪 References OK"
+                    @if let Some(latency_ms) = status_store.reference_latency_ms() {
+                        " (" (latency_ms) "ms)"
+                    }
+                } @else if down == references.len() {
+                    "⚠️ Every reference onion is failing: endpoint failures are likely a local or Tor problem"
+                } @else {
+                    "⚠️ Some reference onions are failing"
+                }
+            }
+            div class="proxy-list" {
+                @for reference in references {
+                    span class={"proxy-item " (reference.status.css_class())} title=(reference_title(reference)) {
+                        (reference.status.status_emoji()) " " (reference.name)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn scheduler_banner(scheduler: &SchedulerStats) -> Markup {
+    let recent_problem = scheduler
+        .last_problem
+        .is_some_and(|at| Utc::now() - at < SCHEDULER_WARNING);
+    let banner_class = if recent_problem {
+        "proxy-banner proxy-banner-degraded"
+    } else {
+        "proxy-banner"
+    };
+
+    html! {
+        div class=(banner_class) {
+            span class="proxy-banner-title" {
+                @if recent_problem {
+                    "⚠️ Monitoring is falling behind schedule"
+                } @else {
+                    "⏱️ Monitoring on schedule"
+                }
+            }
+            div class="proxy-list" {
+                @if let Some(last_cycle_ms) = scheduler.last_cycle_ms {
+                    span class="proxy-item" title="How long the checks due within the last interval took to finish, of the interval" {
+                        "Cycle " (format!("{:.1}", last_cycle_ms as f64 / 1000.0)) "s of "
+                        (scheduler.interval_seconds) "s"
+                    }
+                }
+                span class="proxy-item" title="Intervals whose checks took longer than the interval to finish" {
+                    (scheduler.overruns) " of " (scheduler.cycles) " cycles overran"
+                }
+                span class="proxy-item" title="Checks that started more than a second after they were due" {
+                    (scheduler.late_checks) " late checks"
+                    @if scheduler.late_checks > 0 {
+                        " (up to " (format!("{:.1}", scheduler.max_lateness_ms as f64 / 1000.0)) "s)"
+                    }
+                }
+                span class="proxy-item" title="Checks dropped because the previous one was still running or the scheduler fell behind" {
+                    (scheduler.skipped_checks) " skipped checks"
+                }
+                @if let Some(last_problem) = scheduler.last_problem {
+                    span class="proxy-item" title=(format_timestamp(&last_problem)) {
+                        "Last problem " (format_duration((Utc::now() - last_problem).num_seconds().max(0) as u64)) " ago"
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn reference_title(reference: &ReferenceStatus) -> String {
+    let status = &reference.status;
+    let result = if let Some(response_time_ms) = status.response_time_ms() {
+        format!("{}ms", response_time_ms)
+    } else if let Some((_, error)) = status.failure() {
+        error.to_string()
+    } else {
+        status.status_text().to_string()
+    };
+    format!("{}:{} - {}", reference.address, reference.port, result)
+}
+
+fn tor_banner(tor: &TorStatus) -> Markup {
+    let problem = tor.problem();
+    let banner_class = if problem.is_none() {
+        "proxy-banner"
+    } else if tor.error.is_some() {
+        "proxy-banner proxy-banner-down"
+    } else {
+        "proxy-banner proxy-banner-degraded"
+    };
+
+    html! {
+        div class=(banner_class) title={ "Last check: " (format_timestamp(&tor.last_check)) } {
+            span class="proxy-banner-title" {
+                @if let Some(problem) = &problem {
+                    "⚠️ Tor: " (problem)
+                } @else {
+                    "🧅 Tor OK"
+                }
+            }
+            @if tor.error.is_none() {
+                div class="proxy-list" {
+                    span class="proxy-item" {
+                        "Bootstrap "
+                        @if let Some(progress) = tor.bootstrap_progress {
+                            (progress) "%"
+                        } @else {
+                            "?"
+                        }
+                    }
+                    span class="proxy-item" {
+                        @match tor.circuit_established {
+                            Some(true) => "🟢 Circuits established",
+                            Some(false) => "🔴 No circuits",
+                            None => "⚪ Circuits unknown",
+                        }
+                    }
+                    span class="proxy-item" {
+                        @match tor.network_liveness.as_deref() {
+                            Some("up") => "🟢 Network up",
+                            Some("down") => "🔴 Network down",
+                            _ => "⚪ Network unknown",
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn endpoint_row(endpoint_info: &EndpointInfo) -> Markup {
     let status = &endpoint_info.status;
     let endpoint = &endpoint_info.endpoint;
+    let uptime = uptime(&endpoint_info.samples, Utc::now());
 
     html! {
         tr class=(status.css_class()) {
@@ -199,23 +974,41 @@ fn endpoint_row(endpoint_info: &EndpointInfo) -> Markup {
                 span class="status-indicator" {
                     span class="status-emoji" { (status.status_emoji()) }
                     span class="status-text" { (status.status_text()) }
+                    @if endpoint_info.checking {
+                        span class="spinner" title="Checking now" {}
+                    }
+                }
+                @if let Some(since) = since_text(endpoint_info) {
+                    div class="status-since" { (since) }
                 }
             }
-            td class="name-cell" { (endpoint.name) }
+            td class="name-cell" {
+                a href=(format!("/endpoint/{}", endpoint_key(endpoint))) class="endpoint-link" { (endpoint.name) }
+            }
             td class="address-cell" {
                 code class="address-value clickable-address" data-address=(endpoint.address) title="Click to copy address" { (endpoint.address) }
             }
             td class="port-cell" { (endpoint.port) }
             td class="response-cell" {
-                @match status {
-                    EndpointStatus::Online { response_time_ms } => {
-                        span class="response-time" { (response_time_ms) "ms" }
+                @if let Some(response_time_ms) = status.response_time_ms() {
+                    span class="response-time" { (response_time_ms) "ms" }
+                } @else {
+                    span class="no-data" { "—" }
+                }
+                @if let Some(relative_latency) = endpoint_info.relative_latency {
+                    div class="relative-latency" title="Response time relative to the reference onions" {
+                        (format!("×{:.1}", relative_latency)) " reference"
                     }
-                    _ => {
-                        span class="no-data" { "—" }
+                }
+                @if let Some(anomaly) = &endpoint_info.anomaly {
+                    div class="slower-than-usual" title={ "Usually ~" (anomaly.baseline_ms) "ms" } {
+                        "🐢 Slower than usual"
                     }
                 }
             }
+            @for percent in [uptime.day, uptime.week, uptime.month] {
+                td class="uptime-cell" { (uptime_value(percent)) }
+            }
             td class="time-cell" {
                 @if let Some(last_check) = &endpoint_info.last_check {
                     span class="timestamp" { (format_timestamp(last_check)) }
@@ -225,21 +1018,122 @@ fn endpoint_row(endpoint_info: &EndpointInfo) -> Markup {
             }
             td class="details-cell" {
                 @match status {
-                    EndpointStatus::Offline { error } => {
+                    EndpointStatus::Offline { error, kind } => {
+                        span class="failure-kind" { (kind.label()) }
                         span class="error-message" { (error) }
+                        @if let Some(descriptor) = &endpoint_info.descriptor {
+                            div class="descriptor-summary" { "📜 " (descriptor.summary()) }
+                        }
+                        @if endpoint_info.local_problem {
+                            div class="local-problem" { "⚠️ The reference onions are failing too" }
+                        }
+                    }
+                    EndpointStatus::Failing { error, kind, failures, threshold } => {
+                        span class="failure-kind" { (kind.label()) }
+                        span class="error-message" { (error) }
+                        div class="streak" { (failures) " of " (threshold) " failed checks before offline" }
+                    }
+                    EndpointStatus::Recovering { successes, threshold, .. } => {
+                        span class="streak" { (successes) " of " (threshold) " successful checks before online" }
                     }
-                    EndpointStatus::Checking => {
-                        span class="checking-message" { "Connecting..." }
+                    EndpointStatus::Degraded { reason, .. } => {
+                        span class="degraded-reason" { (reason) }
+                    }
+                    EndpointStatus::Flapping { changes, window_seconds, last } => {
+                        span class="streak" { (changes) " up/down changes in the last " (format_duration(*window_seconds)) }
+                        div class="flapping-last" { "Last check: " (last.status_emoji()) " " (last.status_text()) }
+                    }
+                    EndpointStatus::ProxyUnavailable { reason } => {
+                        span class="proxy-unavailable-message" { "Proxy unavailable: " (reason) }
                     }
                     _ => {
                         span class="no-data" { "—" }
                     }
                 }
+                @if !endpoint_info.vantages.is_empty() {
+                    div class="vantages" {
+                        @for vantage in &endpoint_info.vantages {
+                            span class={"vantage " (vantage.status.css_class())} title=(vantage_title(vantage)) {
+                                (vantage.status.status_emoji()) " " (vantage.proxy)
+                            }
+                        }
+                    }
+                }
             }
         }
     }
 }
 
+fn uptime_value(percent: Option<f64>) -> Markup {
+    let Some(percent) = percent else {
+        return html! { span class="no-data" { "—" } };
+    };
+    let class = if percent >= 99.0 {
+        "uptime uptime-good"
+    } else if percent >= 95.0 {
+        "uptime uptime-fair"
+    } else {
+        "uptime uptime-poor"
+    };
+    // Round down, so anything short of every check succeeding never shows as 100%
+    let shown = (percent * 10.0).floor() / 10.0;
+    html! {
+        span class=(class) {
+            @if percent >= 100.0 { "100%" } @else { (format!("{:.1}%", shown)) }
+        }
+    }
+}
+
+fn vantage_title(vantage: &VantageResult) -> String {
+    let status = &vantage.status;
+    if let Some(response_time_ms) = status.response_time_ms() {
+        format!("{} ({}ms)", status.status_text(), response_time_ms)
+    } else if let Some((_, error)) = status.failure() {
+        format!("{}: {}", status.status_text(), error)
+    } else {
+        status.status_text().to_string()
+    }
+}
+
+fn endpoint_key(endpoint: &Endpoint) -> String {
+    format!("{}:{}", endpoint.address, endpoint.port)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// "Up for 3h 12m" or "Down for 5m" since the endpoint last went up or down
+fn since_text(endpoint_info: &EndpointInfo) -> Option<String> {
+    let since = endpoint_info.since?;
+    let up = endpoint_info.status.is_up()?;
+    let seconds = (Utc::now() - since).num_seconds().max(0) as u64;
+    Some(format!(
+        "{} for {}",
+        if up { "Up" } else { "Down" },
+        format_duration(seconds)
+    ))
+}
+
+/// The two largest units of a duration, e.g. "3h 12m" or "2d 4h"
+fn format_duration(seconds: u64) -> String {
+    let units = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let mut parts = Vec::new();
+    let mut remaining = seconds;
+    for (size, unit) in units {
+        if remaining >= size || (parts.is_empty() && size == 1) {
+            parts.push(format!("{}{}", remaining / size, unit));
+            remaining %= size;
+        } else if !parts.is_empty() {
+            break;
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    parts.join(" ")
+}
+
 fn format_timestamp(dt: &DateTime<Utc>) -> String {
     dt.format("%Y-%m-%d %H:%M:%S UTC").to_string()
 }
@@ -0,0 +1,1008 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::descriptor::count_introduction_points;
+use crate::dialer::BoxedStream;
+use crate::onion::{ClientAuthKey, OnionAddress};
+use crate::types::{DescriptorDiagnostics, HsDirAttempt, HsDirResult, TorControlConfig, TorStatus};
+
+/// Reply code Tor uses for asynchronous events
+const EVENT_CODE: u16 = 650;
+
+#[derive(Error, Debug)]
+pub enum TorControlError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Control port closed the connection")]
+    Closed,
+    #[error("Malformed control port reply: {0}")]
+    Protocol(String),
+    #[error("Tor replied {code} {message}")]
+    Command { code: u16, message: String },
+    #[error("No supported authentication method (Tor offers {0})")]
+    NoAuthMethod(String),
+    #[error("Failed to read auth cookie {path}: {source}")]
+    Cookie {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("No control port address configured")]
+    NoAddress,
+    #[error("Unix control sockets are not supported on this platform")]
+    UnixUnsupported,
+}
+
+pub type Result<T> = std::result::Result<T, TorControlError>;
+
+/// One line of a control port reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyLine {
+    pub text: String,
+    /// Data block that followed a `+` line, with dot-escaping removed
+    pub data: Option<String>,
+}
+
+/// A complete control port reply or asynchronous event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<ReplyLine>,
+}
+
+impl Reply {
+    pub fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    /// Text of the last line, which carries the status message (or the event for `650`)
+    pub fn message(&self) -> &str {
+        self.lines
+            .last()
+            .map(|line| line.text.as_str())
+            .unwrap_or("")
+    }
+}
+
+/// An onion service created with `ADD_ONION`
+#[derive(Debug, Clone)]
+pub struct AddedOnion {
+    /// Address without the `.onion` suffix
+    pub service_id: String,
+    /// Newly generated key as `ED25519-V3:<base64>`, only set when Tor created one
+    pub private_key: Option<String>,
+}
+
+/// Client for Tor's control protocol (control-spec.txt)
+///
+/// Works over any byte stream, so besides TCP and Unix sockets it can be driven by a scripted
+/// stand-in for tests. Asynchronous events that arrive while waiting for a command reply are
+/// queued and handed out by [`TorControl::next_event`].
+pub struct TorControl<S> {
+    stream: BufReader<S>,
+    events: VecDeque<Reply>,
+}
+
+impl TorControl<BoxedStream> {
+    /// Connect to the configured control port and authenticate
+    pub async fn connect(config: &TorControlConfig) -> Result<Self> {
+        let stream: BoxedStream = if let Some(address) = &config.address {
+            Box::new(TcpStream::connect(address.as_str()).await?)
+        } else if let Some(path) = &config.unix_socket {
+            connect_unix(path).await?
+        } else {
+            return Err(TorControlError::NoAddress);
+        };
+
+        let mut control = TorControl::new(stream);
+        control
+            .authenticate(config.password.as_deref(), config.cookie_file.as_deref())
+            .await?;
+        Ok(control)
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> Result<BoxedStream> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &Path) -> Result<BoxedStream> {
+    Err(TorControlError::UnixUnsupported)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TorControl<S> {
+    /// Wrap an already connected, unauthenticated control stream
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Authenticate with a password, the auth cookie, or no credentials at all
+    ///
+    /// The method is chosen from what `PROTOCOLINFO` reports: a configured password wins,
+    /// then null auth, then the cookie (from `cookie_file` or the path Tor advertises).
+    pub async fn authenticate(
+        &mut self,
+        password: Option<&str>,
+        cookie_file: Option<&Path>,
+    ) -> Result<()> {
+        let protocol_info = self.command("PROTOCOLINFO 1").await?;
+        let auth_line = protocol_info
+            .lines
+            .iter()
+            .find_map(|line| line.text.strip_prefix("AUTH "))
+            .ok_or_else(|| TorControlError::Protocol("PROTOCOLINFO without AUTH".to_string()))?;
+        let auth = parse_key_values(auth_line);
+        let methods = auth.get("METHODS").cloned().unwrap_or_default();
+        let offers = |method: &str| methods.split(',').any(|offered| offered == method);
+
+        let credentials = if let Some(password) = password {
+            quote(password)
+        } else if offers("NULL") {
+            String::new()
+        } else if offers("COOKIE") {
+            let path = cookie_file
+                .map(|path| path.to_string_lossy().into_owned())
+                .or_else(|| auth.get("COOKIEFILE").cloned())
+                .ok_or_else(|| TorControlError::NoAuthMethod(methods.clone()))?;
+            let cookie = tokio::fs::read(&path)
+                .await
+                .map_err(|source| TorControlError::Cookie { path, source })?;
+            cookie.iter().map(|byte| format!("{:02X}", byte)).collect()
+        } else {
+            return Err(TorControlError::NoAuthMethod(methods));
+        };
+
+        self.command(format!("AUTHENTICATE {}", credentials).trim_end())
+            .await?;
+        Ok(())
+    }
+
+    /// Send a command and wait for its reply, failing on non-2xx codes
+    pub async fn command(&mut self, command: &str) -> Result<Reply> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        stream.flush().await?;
+
+        loop {
+            let reply = self.read_reply().await?;
+            if reply.code == EVENT_CODE {
+                self.events.push_back(reply);
+                continue;
+            }
+            if !reply.is_ok() {
+                return Err(TorControlError::Command {
+                    code: reply.code,
+                    message: reply.message().to_string(),
+                });
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Query `GETINFO` keys, returning their values by key
+    pub async fn get_info(&mut self, keys: &[&str]) -> Result<HashMap<String, String>> {
+        let reply = self.command(&format!("GETINFO {}", keys.join(" "))).await?;
+
+        let mut values = HashMap::new();
+        for line in reply.lines {
+            if let Some((key, value)) = line.text.split_once('=') {
+                let value = line.data.unwrap_or_else(|| value.to_string());
+                values.insert(key.to_string(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Subscribe to asynchronous events, replacing any previous subscription
+    pub async fn set_events(&mut self, events: &[&str]) -> Result<()> {
+        self.command(&format!("SETEVENTS {}", events.join(" ")))
+            .await?;
+        Ok(())
+    }
+
+    /// Wait for the next asynchronous event
+    pub async fn next_event(&mut self) -> Result<Reply> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let reply = self.read_reply().await?;
+            if reply.code == EVENT_CODE {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Read Tor's bootstrap progress, circuit and network liveness status
+    pub async fn tor_status(&mut self) -> Result<TorStatus> {
+        let info = self
+            .get_info(&[
+                "status/bootstrap-phase",
+                "status/circuit-established",
+                "network-liveness",
+            ])
+            .await?;
+
+        let bootstrap = info
+            .get("status/bootstrap-phase")
+            .map(|phase| parse_key_values(phase))
+            .unwrap_or_default();
+
+        Ok(TorStatus {
+            bootstrap_progress: bootstrap
+                .get("PROGRESS")
+                .and_then(|progress| progress.parse().ok()),
+            bootstrap_summary: bootstrap.get("SUMMARY").cloned(),
+            circuit_established: info
+                .get("status/circuit-established")
+                .map(|established| established == "1"),
+            network_liveness: info.get("network-liveness").cloned(),
+            error: None,
+            last_check: Utc::now(),
+        })
+    }
+
+    /// Register the key for an onion service that requires client authorization
+    ///
+    /// Tor keeps the key in memory only, replacing any key it already had for the service.
+    pub async fn add_client_auth(
+        &mut self,
+        onion: &OnionAddress,
+        key: &ClientAuthKey,
+    ) -> Result<()> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+            onion.service_id(),
+            key.to_base64()
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Create an onion service forwarding `port` to `target` (`ADD_ONION`)
+    ///
+    /// Passing no `private_key` makes Tor generate a new ED25519-V3 key, which is returned so it
+    /// can be reused. Tor removes the service when this control connection closes.
+    pub async fn add_onion(
+        &mut self,
+        private_key: Option<&str>,
+        port: u16,
+        target: SocketAddr,
+    ) -> Result<AddedOnion> {
+        let reply = self
+            .command(&format!(
+                "ADD_ONION {} Port={},{}",
+                private_key.unwrap_or("NEW:ED25519-V3"),
+                port,
+                target
+            ))
+            .await?;
+
+        let values: HashMap<&str, &str> = reply
+            .lines
+            .iter()
+            .filter_map(|line| line.text.split_once('='))
+            .collect();
+        let service_id = values
+            .get("ServiceID")
+            .ok_or_else(|| TorControlError::Protocol("ADD_ONION reply without ServiceID".into()))?;
+        Ok(AddedOnion {
+            service_id: service_id.to_string(),
+            private_key: values.get("PrivateKey").map(|key| key.to_string()),
+        })
+    }
+
+    /// Fetch an onion service's descriptor with `HSFETCH`, recording progress in `diagnostics`
+    ///
+    /// Follows the `HS_DESC` events for the service until an HSDir answered and, if the
+    /// descriptor was received, its content has been examined.
+    pub async fn fetch_descriptor(
+        &mut self,
+        onion: &OnionAddress,
+        diagnostics: &mut DescriptorDiagnostics,
+    ) -> Result<()> {
+        let service_id = onion.service_id();
+        self.set_events(&["HS_DESC", "HS_DESC_CONTENT"]).await?;
+        self.command(&format!("HSFETCH {}", service_id)).await?;
+
+        loop {
+            let event = self.next_event().await?;
+            let Some(first_line) = event.lines.first() else {
+                continue;
+            };
+            let mut words = first_line.text.split(' ');
+
+            match (words.next(), words.next()) {
+                // HS_DESC Action HSAddress AuthType HsDir [DescriptorID] [REASON=...] ...
+                (Some("HS_DESC"), Some(action)) => {
+                    let (Some(address), _auth_type, Some(hsdir)) =
+                        (words.next(), words.next(), words.next())
+                    else {
+                        continue;
+                    };
+                    if address != service_id {
+                        continue;
+                    }
+                    let result = match action {
+                        "REQUESTED" => HsDirResult::Requested,
+                        "RECEIVED" => HsDirResult::Received,
+                        "FAILED" => HsDirResult::Failed {
+                            reason: parse_key_values(&words.collect::<Vec<_>>().join(" "))
+                                .remove("REASON")
+                                .unwrap_or_else(|| "UNKNOWN".to_string()),
+                        },
+                        _ => continue,
+                    };
+                    record_hsdir_result(diagnostics, hsdir, result);
+
+                    let pending = diagnostics
+                        .hsdirs
+                        .iter()
+                        .any(|attempt| attempt.result == HsDirResult::Requested);
+                    if !diagnostics.fetched && !pending && action == "FAILED" {
+                        return Ok(());
+                    }
+                }
+                // HS_DESC_CONTENT HSAddress DescId HsDir, followed by the descriptor
+                (Some("HS_DESC_CONTENT"), Some(address)) if address == service_id => {
+                    let Some(content) = first_line.data.as_deref().filter(|data| !data.is_empty())
+                    else {
+                        continue;
+                    };
+                    diagnostics.fetched = true;
+                    match count_introduction_points(content, onion) {
+                        Ok(count) => diagnostics.introduction_points = Some(count),
+                        Err(error) => diagnostics.error = Some(error.to_string()),
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            // Checked, as slicing inside a multi-byte character would panic
+            let (Some(code), Some(&separator), Some(text)) =
+                (line.get(..3), line.as_bytes().get(3), line.get(4..))
+            else {
+                return Err(TorControlError::Protocol(line));
+            };
+            let code = code
+                .parse::<u16>()
+                .map_err(|_| TorControlError::Protocol(line.clone()))?;
+            let text = text.to_string();
+
+            match separator {
+                b'-' => lines.push(ReplyLine { text, data: None }),
+                b'+' => {
+                    let data = self.read_data().await?;
+                    lines.push(ReplyLine {
+                        text,
+                        data: Some(data),
+                    });
+                }
+                b' ' => {
+                    lines.push(ReplyLine { text, data: None });
+                    return Ok(Reply { code, lines });
+                }
+                _ => return Err(TorControlError::Protocol(line)),
+            }
+        }
+    }
+
+    /// Read a dot-terminated data block
+    async fn read_data(&mut self) -> Result<String> {
+        let mut data = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line == "." {
+                return Ok(data.join("\n"));
+            }
+            let line = line.strip_prefix('.').unwrap_or(&line).to_string();
+            data.push(line);
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(TorControlError::Closed);
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Connect to the control port and read Tor's status in one go
+pub async fn fetch_tor_status(config: &TorControlConfig) -> Result<TorStatus> {
+    let mut control = TorControl::connect(config).await?;
+    control.tor_status().await
+}
+
+/// Fetch an onion service's descriptor on a dedicated control connection
+///
+/// Gives up after `wait`, keeping whatever the HSDirs reported until then.
+pub async fn fetch_descriptor(
+    config: &TorControlConfig,
+    onion: &OnionAddress,
+    wait: Duration,
+) -> DescriptorDiagnostics {
+    let mut diagnostics = DescriptorDiagnostics {
+        fetched: false,
+        hsdirs: Vec::new(),
+        introduction_points: None,
+        error: None,
+        checked_at: Utc::now(),
+    };
+
+    let fetch = async {
+        let mut control = TorControl::connect(config).await?;
+        control.fetch_descriptor(onion, &mut diagnostics).await
+    };
+    let error = match tokio::time::timeout(wait, fetch).await {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(error.to_string()),
+        Err(_) => Some(format!(
+            "Descriptor fetch timed out after {}s",
+            wait.as_secs()
+        )),
+    };
+    if error.is_some() {
+        diagnostics.error = error;
+    }
+
+    diagnostics
+}
+
+/// Update the HSDir's outstanding request with `result`, or add it if Tor never reported one
+fn record_hsdir_result(diagnostics: &mut DescriptorDiagnostics, hsdir: &str, result: HsDirResult) {
+    if result == HsDirResult::Received {
+        diagnostics.fetched = true;
+    }
+    let outstanding = diagnostics
+        .hsdirs
+        .iter_mut()
+        .rev()
+        .find(|attempt| attempt.hsdir == hsdir && attempt.result == HsDirResult::Requested);
+    match outstanding {
+        Some(attempt) if result != HsDirResult::Requested => attempt.result = result,
+        _ => diagnostics.hsdirs.push(HsDirAttempt {
+            hsdir: hsdir.to_string(),
+            result,
+        }),
+    }
+}
+
+/// Register client authorization keys for several onion services on one control connection
+pub async fn register_client_auth(
+    config: &TorControlConfig,
+    keys: &[(OnionAddress, ClientAuthKey)],
+) -> Result<()> {
+    let mut control = TorControl::connect(config).await?;
+    for (onion, key) in keys {
+        control.add_client_auth(onion, key).await?;
+    }
+    Ok(())
+}
+
+/// Ask Tor to use new circuits for new streams (`SIGNAL NEWNYM`)
+pub async fn signal_newnym(config: &TorControlConfig) -> Result<()> {
+    let mut control = TorControl::connect(config).await?;
+    control.command("SIGNAL NEWNYM").await?;
+    Ok(())
+}
+
+/// Parse space separated `KEY=VALUE` pairs, where values may be quoted strings
+pub fn parse_key_values(text: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let token_end = rest.find([' ', '=']).unwrap_or(rest.len());
+        let key = &rest[..token_end];
+        rest = &rest[token_end..];
+
+        let Some(after_equals) = rest.strip_prefix('=') else {
+            // Bare word such as the status severity in "NOTICE BOOTSTRAP ..."
+            rest = rest.trim_start();
+            continue;
+        };
+
+        let value;
+        (value, rest) = if let Some(quoted) = after_equals.strip_prefix('"') {
+            let (value, consumed) = unquote(quoted);
+            (value, &quoted[consumed..])
+        } else {
+            let value_end = after_equals.find(' ').unwrap_or(after_equals.len());
+            (
+                after_equals[..value_end].to_string(),
+                &after_equals[value_end..],
+            )
+        };
+        values.insert(key.to_string(), value);
+        rest = rest.trim_start();
+    }
+
+    values
+}
+
+/// Decode a quoted string body (after the opening quote), returning it and the bytes consumed
+fn unquote(quoted: &str) -> (String, usize) {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return (value, index + 1),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        other => other,
+                    });
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    (value, quoted.len())
+}
+
+/// Encode a string as a control protocol quoted string
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// In-memory control port that answers commands from a script
+    ///
+    /// Each command gets the reply of the most recently registered prefix it starts with, or
+    /// `510 Unrecognized command`. Replies are sent verbatim (line endings are normalized), so a
+    /// script can include data blocks or `650` events ahead of the final reply line. Null auth is
+    /// offered unless `PROTOCOLINFO` is scripted otherwise.
+    #[derive(Debug, Clone)]
+    struct ScriptedControlPort {
+        responses: Arc<Mutex<Vec<(String, String)>>>,
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ScriptedControlPort {
+        fn new() -> Self {
+            let port = Self {
+                responses: Arc::new(Mutex::new(Vec::new())),
+                commands: Arc::new(Mutex::new(Vec::new())),
+            };
+            port.respond(
+                "PROTOCOLINFO",
+                "250-PROTOCOLINFO 1\n250-AUTH METHODS=NULL\n250-VERSION Tor=\"0.4.8.12\"\n250 OK",
+            );
+            port.respond("AUTHENTICATE", "250 OK");
+            port
+        }
+
+        /// Answer commands starting with `prefix` with `reply`
+        fn respond(&self, prefix: &str, reply: &str) {
+            self.responses
+                .lock()
+                .unwrap()
+                .push((prefix.to_string(), reply.to_string()));
+        }
+
+        /// Commands received so far, in order
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+
+        /// Open a new connection to the scripted port
+        fn connect(&self) -> DuplexStream {
+            let (stream, peer) = tokio::io::duplex(16 * 1024);
+            let port = self.clone();
+            tokio::spawn(async move {
+                let mut peer = BufReader::new(peer);
+                let mut line = String::new();
+                while matches!(peer.read_line(&mut line).await, Ok(read) if read > 0) {
+                    let command = line.trim_end_matches(['\r', '\n']).to_string();
+                    line.clear();
+                    let reply = port.reply_for(&command);
+                    port.commands.lock().unwrap().push(command);
+                    if peer.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            stream
+        }
+
+        fn reply_for(&self, command: &str) -> String {
+            let responses = self.responses.lock().unwrap();
+            let reply = responses
+                .iter()
+                .rev()
+                .find(|(prefix, _)| command.starts_with(prefix.as_str()))
+                .map(|(_, reply)| reply.as_str())
+                .unwrap_or("510 Unrecognized command");
+            reply
+                .lines()
+                .map(|reply_line| format!("{}\r\n", reply_line))
+                .collect()
+        }
+    }
+
+    /// Scripted port whose `PROTOCOLINFO` offers `auth`
+    fn port_offering(auth: &str) -> ScriptedControlPort {
+        let port = ScriptedControlPort::new();
+        port.respond(
+            "PROTOCOLINFO",
+            &format!(
+                "250-PROTOCOLINFO 1\n250-AUTH {}\n250-VERSION Tor=\"0.4.8.12\"\n250 OK",
+                auth
+            ),
+        );
+        port
+    }
+
+    async fn authenticate(
+        port: &ScriptedControlPort,
+        password: Option<&str>,
+        cookie_file: Option<&Path>,
+    ) -> Result<()> {
+        TorControl::new(port.connect())
+            .authenticate(password, cookie_file)
+            .await
+    }
+
+    #[tokio::test]
+    async fn authenticate_prefers_password() {
+        let port = port_offering("METHODS=NULL,HASHEDPASSWORD");
+        authenticate(&port, Some("se\"cret"), None).await.unwrap();
+        assert_eq!(
+            port.commands(),
+            vec!["PROTOCOLINFO 1", "AUTHENTICATE \"se\\\"cret\""]
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_uses_null_auth_before_cookie() {
+        let port = port_offering("METHODS=COOKIE,NULL COOKIEFILE=\"/nonexistent\"");
+        authenticate(&port, None, None).await.unwrap();
+        assert_eq!(port.commands(), vec!["PROTOCOLINFO 1", "AUTHENTICATE"]);
+    }
+
+    #[tokio::test]
+    async fn authenticate_sends_cookie_from_advertised_file() {
+        let path = std::env::temp_dir().join(format!("control_auth_cookie_{}", std::process::id()));
+        std::fs::write(&path, [0x01, 0xab, 0xff]).unwrap();
+        let port = port_offering(&format!(
+            "METHODS=COOKIE,SAFECOOKIE COOKIEFILE={}",
+            quote(&path.to_string_lossy())
+        ));
+
+        let result = authenticate(&port, None, None).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(
+            port.commands(),
+            vec!["PROTOCOLINFO 1", "AUTHENTICATE 01ABFF"]
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_without_usable_method_fails() {
+        let port = port_offering("METHODS=HASHEDPASSWORD");
+        let error = authenticate(&port, None, None).await.unwrap_err();
+        assert!(
+            matches!(error, TorControlError::NoAuthMethod(methods) if methods == "HASHEDPASSWORD")
+        );
+        assert_eq!(port.commands(), vec!["PROTOCOLINFO 1"]);
+    }
+
+    #[tokio::test]
+    async fn authenticate_reports_rejected_credentials() {
+        let port = ScriptedControlPort::new();
+        port.respond(
+            "AUTHENTICATE",
+            "515 Authentication failed: Password did not match",
+        );
+        let error = authenticate(&port, Some("wrong"), None).await.unwrap_err();
+        assert!(matches!(error, TorControlError::Command { code: 515, .. }));
+    }
+
+    #[tokio::test]
+    async fn read_reply_collects_multi_line_and_data_replies() {
+        let port = ScriptedControlPort::new();
+        port.respond(
+            "GETINFO",
+            "250-status/circuit-established=1\n250+config-text=\nSocksPort 9050\n..hidden\n.\n250 OK",
+        );
+        let mut control = TorControl::new(port.connect());
+
+        let reply = control
+            .command("GETINFO status/circuit-established config-text")
+            .await
+            .unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(
+            reply.lines,
+            vec![
+                ReplyLine {
+                    text: "status/circuit-established=1".to_string(),
+                    data: None,
+                },
+                ReplyLine {
+                    text: "config-text=".to_string(),
+                    data: Some("SocksPort 9050\n.hidden".to_string()),
+                },
+                ReplyLine {
+                    text: "OK".to_string(),
+                    data: None,
+                },
+            ]
+        );
+        assert_eq!(reply.message(), "OK");
+    }
+
+    #[tokio::test]
+    async fn malformed_reply_lines_are_protocol_errors() {
+        for reply in ["25", "2€0 OK", "250€OK", "2x0 OK", "250/OK"] {
+            let port = ScriptedControlPort::new();
+            port.respond("GETINFO", reply);
+            let mut control = TorControl::new(port.connect());
+
+            let result = control.command("GETINFO version").await;
+            assert!(
+                matches!(result, Err(TorControlError::Protocol(ref line)) if line == reply),
+                "{reply:?}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn get_info_prefers_data_blocks() {
+        let port = ScriptedControlPort::new();
+        port.respond(
+            "GETINFO",
+            "250-network-liveness=up\n250+config-text=\nSocksPort 9050\n.\n250 OK",
+        );
+        let mut control = TorControl::new(port.connect());
+
+        let info = control
+            .get_info(&["network-liveness", "config-text"])
+            .await
+            .unwrap();
+        assert_eq!(info["network-liveness"], "up");
+        assert_eq!(info["config-text"], "SocksPort 9050");
+    }
+
+    #[tokio::test]
+    async fn events_during_a_command_are_queued() {
+        let port = ScriptedControlPort::new();
+        port.respond(
+            "SIGNAL",
+            "650 STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED\n250 OK",
+        );
+        let mut control = TorControl::new(port.connect());
+
+        let reply = control.command("SIGNAL NEWNYM").await.unwrap();
+        assert!(reply.is_ok());
+        let event = control.next_event().await.unwrap();
+        assert_eq!(event.code, EVENT_CODE);
+        assert_eq!(event.message(), "STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED");
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_fail() {
+        let port = ScriptedControlPort::new();
+        let mut control = TorControl::new(port.connect());
+        let error = control.command("FROBNICATE").await.unwrap_err();
+        assert!(matches!(error, TorControlError::Command { code: 510, .. }));
+    }
+
+    /// Identity key of the client authorization descriptor in `testdata/hsdesc2.txt`
+    const DESCRIPTOR_IDENTITY: [u8; 32] = [
+        0x78, 0x1d, 0x97, 0x8c, 0xe6, 0xce, 0x9c, 0xaa, 0x8b, 0xca, 0x30, 0x6f, 0x53, 0xe8, 0x2d,
+        0x2c, 0x99, 0x3e, 0x5c, 0x91, 0x34, 0x66, 0x25, 0xf1, 0xc1, 0x51, 0xdc, 0xfc, 0x56, 0xd7,
+        0x53, 0xd3,
+    ];
+
+    fn empty_diagnostics() -> DescriptorDiagnostics {
+        DescriptorDiagnostics {
+            fetched: false,
+            hsdirs: Vec::new(),
+            introduction_points: None,
+            error: None,
+            checked_at: Utc::now(),
+        }
+    }
+
+    /// Scripted port that answers `HSFETCH` for `onion` with `events`
+    fn port_fetching(onion: &OnionAddress, events: &str) -> ScriptedControlPort {
+        let port = ScriptedControlPort::new();
+        port.respond("SETEVENTS", "250 OK");
+        port.respond(
+            &format!("HSFETCH {}", onion.service_id()),
+            &format!("250 OK\n{}", events),
+        );
+        port
+    }
+
+    fn attempt(hsdir: &str, result: HsDirResult) -> HsDirAttempt {
+        HsDirAttempt {
+            hsdir: hsdir.to_string(),
+            result,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_descriptor_collects_content_of_the_service() {
+        let onion = OnionAddress::from_public_key(DESCRIPTOR_IDENTITY);
+        let other = OnionAddress::from_public_key([7; 32]);
+        let (id, other_id) = (onion.service_id(), other.service_id());
+        let descriptor = include_str!("../testdata/hsdesc2.txt");
+        let port = port_fetching(
+            &onion,
+            &format!(
+                "650 HS_DESC REQUESTED {id} NO_AUTH $AAAA~dir1 descid\n\
+                 650 HS_DESC FAILED {other_id} NO_AUTH $BBBB~dir2 descid REASON=NOT_FOUND\n\
+                 650+HS_DESC_CONTENT {other_id} descid $BBBB~dir2\nnot this one\n.\n650 OK\n\
+                 650 HS_DESC RECEIVED {id} NO_AUTH $AAAA~dir1 descid\n\
+                 650+HS_DESC_CONTENT {id} descid $AAAA~dir1\n{descriptor}.\n650 OK"
+            ),
+        );
+        let mut control = TorControl::new(port.connect());
+        let mut diagnostics = empty_diagnostics();
+
+        control
+            .fetch_descriptor(&onion, &mut diagnostics)
+            .await
+            .unwrap();
+        assert_eq!(
+            port.commands(),
+            vec![
+                "SETEVENTS HS_DESC HS_DESC_CONTENT",
+                &format!("HSFETCH {id}")
+            ]
+        );
+        assert!(diagnostics.fetched);
+        assert_eq!(
+            diagnostics.hsdirs,
+            vec![attempt("$AAAA~dir1", HsDirResult::Received)]
+        );
+        // The whole multi-line descriptor arrived: its outer layer decrypted, and only the
+        // inner one needs the client authorization key
+        assert_eq!(
+            diagnostics.error,
+            Some(crate::descriptor::DescriptorError::MacMismatch("inner").to_string())
+        );
+        assert_eq!(diagnostics.introduction_points, None);
+    }
+
+    #[tokio::test]
+    async fn fetch_descriptor_stops_when_every_hsdir_failed() {
+        let onion = OnionAddress::from_public_key(DESCRIPTOR_IDENTITY);
+        let id = onion.service_id();
+        let port = port_fetching(
+            &onion,
+            &format!(
+                "650 HS_DESC REQUESTED {id} NO_AUTH $AAAA~dir1 descid\n\
+                 650 HS_DESC REQUESTED {id} NO_AUTH $BBBB~dir2 descid\n\
+                 650 HS_DESC FAILED {id} NO_AUTH $AAAA~dir1 descid REASON=NOT_FOUND\n\
+                 650 HS_DESC FAILED {id} NO_AUTH $BBBB~dir2 descid"
+            ),
+        );
+        let mut control = TorControl::new(port.connect());
+        let mut diagnostics = empty_diagnostics();
+
+        control
+            .fetch_descriptor(&onion, &mut diagnostics)
+            .await
+            .unwrap();
+        assert!(!diagnostics.fetched);
+        assert_eq!(
+            diagnostics.hsdirs,
+            vec![
+                attempt(
+                    "$AAAA~dir1",
+                    HsDirResult::Failed {
+                        reason: "NOT_FOUND".to_string()
+                    }
+                ),
+                attempt(
+                    "$BBBB~dir2",
+                    HsDirResult::Failed {
+                        reason: "UNKNOWN".to_string()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_descriptor_waits_for_content_after_received() {
+        let onion = OnionAddress::from_public_key(DESCRIPTOR_IDENTITY);
+        let id = onion.service_id();
+        let port = port_fetching(
+            &onion,
+            &format!(
+                "650 HS_DESC REQUESTED {id} NO_AUTH $AAAA~dir1 descid\n\
+                 650 HS_DESC RECEIVED {id} NO_AUTH $AAAA~dir1 descid\n\
+                 650+HS_DESC_CONTENT {id} descid $AAAA~dir1\n.\n650 OK"
+            ),
+        );
+        let mut control = TorControl::new(port.connect());
+        let mut diagnostics = empty_diagnostics();
+
+        // Empty content isn't the descriptor, so the fetch keeps waiting until the caller gives up
+        let fetch = control.fetch_descriptor(&onion, &mut diagnostics);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(30), fetch)
+                .await
+                .is_err()
+        );
+        assert!(diagnostics.fetched);
+        assert_eq!(
+            diagnostics.hsdirs,
+            vec![attempt("$AAAA~dir1", HsDirResult::Received)]
+        );
+        assert_eq!(diagnostics.introduction_points, None);
+        assert_eq!(diagnostics.error, None);
+    }
+
+    #[test]
+    fn parse_key_values_handles_bare_words_and_quotes() {
+        let values = parse_key_values(
+            "NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done \\\"really\\\"\" COUNT=3",
+        );
+        assert_eq!(values.len(), 4);
+        assert_eq!(values["PROGRESS"], "100");
+        assert_eq!(values["TAG"], "done");
+        assert_eq!(values["SUMMARY"], "Done \"really\"");
+        assert_eq!(values["COUNT"], "3");
+    }
+
+    #[test]
+    fn parse_key_values_of_empty_text() {
+        assert!(parse_key_values("").is_empty());
+        assert!(parse_key_values("  NOTICE  ").is_empty());
+    }
+
+    #[test]
+    fn unquote_decodes_escapes() {
+        assert_eq!(
+            unquote(r#"a\nb\tc\\d\"e" rest"#),
+            ("a\nb\tc\\d\"e".to_string(), 14)
+        );
+        // An unterminated string takes the rest of the text
+        assert_eq!(unquote("open"), ("open".to_string(), 4));
+    }
+
+    #[test]
+    fn quote_round_trips_through_unquote() {
+        let value = r#"pass "word" \ end"#;
+        let quoted = quote(value);
+        assert_eq!(unquote(&quoted[1..]), (value.to_string(), quoted.len() - 1));
+    }
+}
@@ -1,32 +1,237 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::onion::{ClientAuthKey, OnionAddress};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
-    pub proxy: ProxyConfig,
+    /// Single proxy, kept for configs written before `[[proxies]]` existed
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub proxies: Vec<ProxyConfig>,
     pub monitoring: MonitoringConfig,
+    /// Optional Tor control port used to report Tor's own health
+    #[serde(default)]
+    pub tor_control: Option<TorControlConfig>,
     pub endpoints: Vec<Endpoint>,
+    /// Onion services probed every cycle as a control group for the endpoints
+    #[serde(default)]
+    pub references: Vec<ReferenceOnion>,
+}
+
+impl Config {
+    /// All configured proxies in failover order, `[proxy]` first
+    pub fn all_proxies(&self) -> Vec<ProxyConfig> {
+        self.proxy
+            .iter()
+            .chain(self.proxies.iter())
+            .cloned()
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Publish the dashboard as an onion service through the Tor control port
+    #[serde(default)]
+    pub onion_service: Option<OnionServiceConfig>,
+}
+
+/// How the dashboard is published with `ADD_ONION`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnionServiceConfig {
+    /// Where the service's private key is kept, created on first start so the address is stable
+    pub key_file: PathBuf,
+    /// Port the service listens on, defaults to 80
+    #[serde(default)]
+    pub port: Option<u16>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyConfig {
+    /// Name shown on the dashboard, defaults to `host:port`
+    #[serde(default)]
+    pub name: Option<String>,
     pub host: String,
     pub port: u16,
 }
 
+impl ProxyConfig {
+    pub fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", self.host, self.port))
+    }
+
+    pub fn socket_addr(&self) -> Result<SocketAddr, AddrParseError> {
+        format!("{}:{}", self.host, self.port).parse()
+    }
+}
+
+/// Where to reach Tor's control port and how to authenticate
+///
+/// Exactly one of `address` or `unix_socket` must be set. Without a `password` the monitor
+/// uses null auth or the auth cookie, whichever Tor offers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TorControlConfig {
+    /// TCP address such as `127.0.0.1:9051`
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Path of a control socket such as `/run/tor/control`
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Auth cookie location, defaults to the path Tor reports in `PROTOCOLINFO`
+    #[serde(default)]
+    pub cookie_file: Option<PathBuf>,
+    /// Fetch the onion service descriptor of endpoints that fail a check
+    #[serde(default)]
+    pub fetch_descriptors: bool,
+}
+
+impl fmt::Debug for TorControlConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the password out of logs
+        f.debug_struct("TorControlConfig")
+            .field("address", &self.address)
+            .field("unix_socket", &self.unix_socket)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .field("cookie_file", &self.cookie_file)
+            .field("fetch_descriptors", &self.fetch_descriptors)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MonitoringConfig {
     pub check_interval_seconds: u64,
     pub connection_timeout_seconds: u64,
+    #[serde(default)]
+    pub proxy_mode: ProxyMode,
+    #[serde(default)]
+    pub fresh_identity: FreshIdentity,
+    /// Extra attempts within one check before it counts as failed
+    #[serde(default)]
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each further one (default 1000)
+    #[serde(default)]
+    pub retry_backoff_ms: Option<u64>,
+    /// Consecutive failed checks before an endpoint is shown as offline (default 1)
+    #[serde(default)]
+    pub failures_before_offline: Option<u32>,
+    /// Consecutive successful checks before an offline endpoint is shown as online (default 1)
+    #[serde(default)]
+    pub successes_before_online: Option<u32>,
+    /// Checks allowed to run at the same time, unlimited by default
+    #[serde(default)]
+    pub max_concurrent_checks: Option<usize>,
+    /// Spread the first check of each endpoint across its interval, in random order
+    #[serde(default)]
+    pub stagger_checks: bool,
+    /// Move each check by up to this percentage of the endpoint's interval, either way
+    #[serde(default)]
+    pub jitter_percent: u8,
+    /// Response time above which an online endpoint counts as degraded
+    #[serde(default)]
+    pub degraded_response_ms: Option<u64>,
+    /// Up/down changes within the flapping window that mark an endpoint as flapping
+    #[serde(default)]
+    pub flapping_changes: Option<usize>,
+    /// Window for counting up/down changes, in seconds (default 3600)
+    #[serde(default)]
+    pub flapping_window_seconds: Option<u64>,
+    /// File to keep check results in across restarts, as JSON lines
+    #[serde(default)]
+    pub history_file: Option<PathBuf>,
+    /// Window for response time percentiles and histograms, in seconds (default 86400)
+    #[serde(default)]
+    pub latency_window_seconds: Option<u64>,
+    /// Standard deviations above its usual response time that make a check slower than usual
+    /// (default 3)
+    #[serde(default)]
+    pub anomaly_deviations: Option<f64>,
+    /// Check endpoints that are having trouble as often as this, in seconds
+    #[serde(default)]
+    pub min_check_interval_seconds: Option<u64>,
+    /// Check endpoints that keep working as rarely as this, in seconds
+    #[serde(default)]
+    pub max_check_interval_seconds: Option<u64>,
+    /// Checks started per minute across all endpoints, unlimited by default
+    #[serde(default)]
+    pub max_checks_per_minute: Option<usize>,
+    /// How long running checks may take to finish on shutdown before they are cancelled
+    #[serde(default)]
+    pub shutdown_grace_seconds: Option<u64>,
+}
+
+impl MonitoringConfig {
+    pub fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms.unwrap_or(1000))
+    }
+
+    pub fn failures_before_offline(&self) -> u32 {
+        self.failures_before_offline.unwrap_or(1)
+    }
+
+    pub fn successes_before_online(&self) -> u32 {
+        self.successes_before_online.unwrap_or(1)
+    }
+
+    pub fn flapping_window(&self) -> Duration {
+        Duration::from_secs(self.flapping_window_seconds.unwrap_or(3600))
+    }
+
+    pub fn latency_window(&self) -> Duration {
+        Duration::from_secs(self.latency_window_seconds.unwrap_or(86400))
+    }
+
+    pub fn anomaly_deviations(&self) -> f64 {
+        self.anomaly_deviations.unwrap_or(3.0)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_seconds.unwrap_or(10))
+    }
+
+    /// Whether check intervals adapt to how endpoints are doing
+    pub fn adaptive(&self) -> bool {
+        self.min_check_interval_seconds.is_some() || self.max_check_interval_seconds.is_some()
+    }
+}
+
+/// How each monitoring cycle is kept off the circuits of the previous one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FreshIdentity {
+    /// Reuse circuits across cycles, as any Tor client would
+    #[default]
+    Off,
+    /// Send `SIGNAL NEWNYM` through the control port at the start of each cycle
+    Newnym,
+    /// Authenticate to the SOCKS port with new credentials each cycle (`IsolateSOCKSAuth`)
+    Isolate,
+}
+
+/// How proxied endpoints are checked when several proxies are configured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyMode {
+    /// Use the first reachable proxy, falling back to the next one when a proxy is down
+    #[default]
+    Failover,
+    /// Check every endpoint through every proxy and show the results side by side
+    Compare,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,50 +239,706 @@ pub struct Endpoint {
     pub name: String,
     pub address: String,
     pub port: u16,
+    #[serde(default)]
+    pub mode: CheckMode,
+    /// Perform the JoinMarket directory handshake after connecting
+    #[serde(default)]
+    pub handshake: bool,
+    /// x25519 private key for onion services that require client authorization, in the
+    /// base32 format of Tor's `.auth_private` files
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_client_auth_key"
+    )]
+    pub client_auth_key: Option<ClientAuthKey>,
+    /// Check this endpoint on its own schedule instead of `monitoring.check_interval_seconds`
+    #[serde(default)]
+    pub check_interval_seconds: Option<u64>,
+    /// Overrides `monitoring.connection_timeout_seconds` for this endpoint
+    #[serde(default)]
+    pub connection_timeout_seconds: Option<u64>,
 }
 
+/// An onion service known to be up, ideally one we run, to tell Tor's state from the endpoints'
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceOnion {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    /// Perform the JoinMarket directory handshake after connecting
+    #[serde(default)]
+    pub handshake: bool,
+}
+
+impl ReferenceOnion {
+    /// The reference as an endpoint, so it can be checked the same way
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            name: self.name.clone(),
+            address: self.address.clone(),
+            port: self.port,
+            mode: CheckMode::Proxy,
+            handshake: self.handshake,
+            client_auth_key: None,
+            check_interval_seconds: None,
+            connection_timeout_seconds: None,
+        }
+    }
+}
+
+impl Endpoint {
+    /// The endpoint's decoded v3 onion address, if it is a valid onion service
+    pub fn onion_address(&self) -> Option<OnionAddress> {
+        OnionAddress::parse(&self.address).ok()
+    }
+
+    /// How often the endpoint is checked
+    pub fn check_interval(&self, monitoring: &MonitoringConfig) -> Duration {
+        Duration::from_secs(
+            self.check_interval_seconds
+                .unwrap_or(monitoring.check_interval_seconds),
+        )
+    }
+
+    /// How long a check of the endpoint may take before it counts as a timeout
+    pub fn connection_timeout(&self, monitoring: &MonitoringConfig) -> Duration {
+        Duration::from_secs(
+            self.connection_timeout_seconds
+                .unwrap_or(monitoring.connection_timeout_seconds),
+        )
+    }
+}
+
+/// Decode the key while loading the config, so the raw text is never kept around
+fn deserialize_client_auth_key<'de, D>(deserializer: D) -> Result<Option<ClientAuthKey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|key| ClientAuthKey::parse(&key).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// How the monitor reaches an endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMode {
+    /// Connect through the configured SOCKS5 proxy
+    #[default]
+    Proxy,
+    /// Connect straight to the endpoint over TCP (clearnet or LAN addresses)
+    Direct,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
 pub enum EndpointStatus {
     Unknown,
-    Checking,
-    Online { response_time_ms: u64 },
-    Offline { error: String },
+    Online {
+        response_time_ms: u64,
+    },
+    /// Reachable, but slower than the configured limit or without completing the handshake
+    Degraded {
+        response_time_ms: u64,
+        reason: String,
+    },
+    Offline {
+        error: String,
+        kind: FailureKind,
+    },
+    /// Went up and down too often within the flapping window; `last` is the latest status
+    Flapping {
+        changes: usize,
+        window_seconds: u64,
+        last: Box<EndpointStatus>,
+    },
+    /// The last checks failed, but not enough of them in a row to call the endpoint offline
+    Failing {
+        error: String,
+        kind: FailureKind,
+        failures: u32,
+        threshold: u32,
+    },
+    /// Reachable again after being offline, but not for enough checks in a row to call it online
+    Recovering {
+        response_time_ms: u64,
+        successes: u32,
+        threshold: u32,
+    },
+    /// The endpoint couldn't be checked because no proxy was reachable
+    ProxyUnavailable {
+        reason: String,
+    },
+}
+
+/// Stage of a check that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureKind {
+    /// The proxy used to reach the endpoint could not be reached
+    Proxy,
+    /// The endpoint's hostname could not be resolved
+    Resolve,
+    /// The connection could not be established
+    Connect,
+    /// Connected, but the application handshake failed
+    Handshake,
+    /// The check did not complete within the connection timeout
+    Timeout,
+    /// Tor reported missing or wrong client authorization for the onion service
+    ClientAuth,
+}
+
+impl FailureKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FailureKind::Proxy => "Proxy",
+            FailureKind::Resolve => "DNS",
+            FailureKind::Connect => "Connect",
+            FailureKind::Handshake => "Handshake",
+            FailureKind::Timeout => "Timeout",
+            FailureKind::ClientAuth => "Client Auth",
+        }
+    }
 }
 
 impl EndpointStatus {
     pub fn status_text(&self) -> &'static str {
         match self {
             EndpointStatus::Unknown => "Unknown",
-            EndpointStatus::Checking => "Checking",
             EndpointStatus::Online { .. } => "Online",
+            EndpointStatus::Degraded { .. } => "Degraded",
             EndpointStatus::Offline { .. } => "Offline",
+            EndpointStatus::Flapping { .. } => "Flapping",
+            EndpointStatus::Failing { .. } => "Failing",
+            EndpointStatus::Recovering { .. } => "Recovering",
+            EndpointStatus::ProxyUnavailable { .. } => "Unknown",
         }
     }
 
     pub fn status_emoji(&self) -> &'static str {
         match self {
             EndpointStatus::Unknown => "⚪",
-            EndpointStatus::Checking => "🟡",
             EndpointStatus::Online { .. } => "🟢",
+            EndpointStatus::Degraded { .. } => "🟡",
             EndpointStatus::Offline { .. } => "🔴",
+            EndpointStatus::Flapping { .. } => "🟣",
+            EndpointStatus::Failing { .. } | EndpointStatus::Recovering { .. } => "🟠",
+            EndpointStatus::ProxyUnavailable { .. } => "⚪",
+        }
+    }
+
+    pub fn response_time_ms(&self) -> Option<u64> {
+        match self {
+            EndpointStatus::Online { response_time_ms }
+            | EndpointStatus::Degraded {
+                response_time_ms, ..
+            }
+            | EndpointStatus::Recovering {
+                response_time_ms, ..
+            } => Some(*response_time_ms),
+            EndpointStatus::Flapping { last, .. } => last.response_time_ms(),
+            _ => None,
+        }
+    }
+
+    pub fn failure(&self) -> Option<(FailureKind, &str)> {
+        match self {
+            EndpointStatus::Offline { error, kind }
+            | EndpointStatus::Failing { error, kind, .. } => Some((*kind, error)),
+            EndpointStatus::Flapping { last, .. } => last.failure(),
+            _ => None,
         }
     }
 
     pub fn css_class(&self) -> &'static str {
         match self {
             EndpointStatus::Unknown => "status-unknown",
-            EndpointStatus::Checking => "status-checking",
             EndpointStatus::Online { .. } => "status-online",
+            EndpointStatus::Degraded { .. } => "status-degraded",
             EndpointStatus::Offline { .. } => "status-offline",
+            EndpointStatus::Flapping { .. } => "status-flapping",
+            EndpointStatus::Failing { .. } => "status-failing",
+            EndpointStatus::Recovering { .. } => "status-recovering",
+            EndpointStatus::ProxyUnavailable { .. } => "status-unknown",
+        }
+    }
+
+    /// Whether the endpoint counts as up, `None` when no check reached a verdict
+    ///
+    /// Failing endpoints are still up and recovering ones still down until the thresholds
+    /// are reached.
+    pub fn is_up(&self) -> Option<bool> {
+        match self {
+            EndpointStatus::Online { .. }
+            | EndpointStatus::Degraded { .. }
+            | EndpointStatus::Failing { .. } => Some(true),
+            EndpointStatus::Offline { .. } | EndpointStatus::Recovering { .. } => Some(false),
+            EndpointStatus::Flapping { last, .. } => last.is_up(),
+            EndpointStatus::Unknown | EndpointStatus::ProxyUnavailable { .. } => None,
         }
     }
 }
 
+/// Time spent in each phase of a check, in milliseconds
+///
+/// Phases that were not reached (or do not apply to the endpoint) are `None`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CheckTimings {
+    /// Resolving a clearnet hostname before connecting (through the proxy for proxied checks)
+    pub resolve_ms: Option<u64>,
+    /// TCP connect to the SOCKS proxy, or to the endpoint itself for direct checks
+    pub tcp_connect_ms: Option<u64>,
+    /// SOCKS5 authentication method negotiation
+    pub auth_ms: Option<u64>,
+    /// Waiting for the SOCKS5 CONNECT reply, which includes Tor circuit and rendezvous setup
+    pub connect_reply_ms: Option<u64>,
+    /// Application handshake, from sending our hello to receiving the complete reply
+    pub handshake_ms: Option<u64>,
+    /// From sending our hello to receiving the first byte of the reply
+    pub first_byte_ms: Option<u64>,
+    /// Local address of the connection to the SOCKS proxy, which identifies the probe's stream
+    /// in Tor's control port events
+    #[serde(skip)]
+    pub proxy_source_addr: Option<SocketAddr>,
+}
+
+impl CheckTimings {
+    /// Time until the connection to the endpoint was up, if the check got that far
+    pub fn connected_ms(&self, mode: CheckMode) -> Option<u64> {
+        match mode {
+            CheckMode::Direct => self.tcp_connect_ms?,
+            CheckMode::Proxy => self.connect_reply_ms?,
+        };
+        Some(
+            [
+                self.resolve_ms,
+                self.tcp_connect_ms,
+                self.auth_ms,
+                self.connect_reply_ms,
+            ]
+            .into_iter()
+            .flatten()
+            .sum(),
+        )
+    }
+}
+
+/// Result of checking an endpoint through one particular proxy
+#[derive(Debug, Clone)]
+pub struct VantageResult {
+    pub proxy: String,
+    pub status: EndpointStatus,
+    pub timings: CheckTimings,
+}
+
 #[derive(Debug, Clone)]
 pub struct EndpointInfo {
     pub endpoint: Endpoint,
+    /// Outcome of the last completed check
     pub status: EndpointStatus,
+    /// A check is in progress; `status` keeps showing the previous result meanwhile
+    pub checking: bool,
     pub last_check: Option<DateTime<Utc>>,
+    pub last_timings: Option<CheckTimings>,
+    /// Per-proxy results of the last check, only filled in [`ProxyMode::Compare`]
+    pub vantages: Vec<VantageResult>,
+    /// Descriptor fetch made after the last failed check, cleared once the endpoint is back
+    pub descriptor: Option<DescriptorDiagnostics>,
+    /// Most recent checks, oldest first
+    pub history: VecDeque<CheckRecord>,
+    /// Failed checks in a row, reset by a successful one
+    pub consecutive_failures: u32,
+    /// Successful checks in a row, reset by a failed one
+    pub consecutive_successes: u32,
+    /// Whether enough failures piled up to call the endpoint offline, until enough successes
+    /// undo it
+    pub confirmed_offline: bool,
+    /// Most recent status changes, oldest first
+    pub transitions: VecDeque<Transition>,
+    /// When the endpoint last went up or down
+    pub since: Option<DateTime<Utc>>,
+    /// Results of the checks over the last 30 days, oldest first
+    pub samples: VecDeque<Sample>,
+    /// Response times over the latency window, updated after each check
+    pub latency: Option<LatencyStats>,
+    /// What the endpoint's response time usually is, learned from its checks
+    pub baseline: Option<LatencyBaseline>,
+    /// The last check was much slower than the baseline
+    pub anomaly: Option<LatencyAnomaly>,
+    /// Response time of the last check divided by that of the reference onions
+    pub relative_latency: Option<f64>,
+    /// The reference onions were failing too when the last check completed
+    pub local_problem: bool,
+    /// How long until the next check, while intervals adapt to the endpoint's status
+    pub adapted_interval: Option<Duration>,
+}
+
+/// Exponentially weighted mean and deviation of an endpoint's response times
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LatencyBaseline {
+    pub mean_ms: f64,
+    pub deviation_ms: f64,
+    /// Response times the baseline was learned from
+    pub samples: u64,
 }
 
-pub type StatusStore = HashMap<String, EndpointInfo>;
+/// A check that took much longer than the endpoint usually does
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LatencyAnomaly {
+    pub response_time_ms: u64,
+    pub baseline_ms: u64,
+}
+
+impl LatencyBaseline {
+    /// Weight of the newest response time; older ones fade out over roughly 1 / weight checks
+    const WEIGHT: f64 = 0.1;
+
+    /// Response times needed before the baseline is trusted to flag anomalies
+    const WARMUP: u64 = 10;
+
+    /// Anomalies also have to be this many times the mean and this much above it, so the
+    /// jitter of endpoints that always answer within a few milliseconds doesn't count
+    const MIN_RATIO: f64 = 1.5;
+    const MIN_EXCESS_MS: f64 = 100.0;
+
+    pub fn new(response_time_ms: u64) -> Self {
+        Self {
+            mean_ms: response_time_ms as f64,
+            deviation_ms: 0.0,
+            samples: 1,
+        }
+    }
+
+    /// Whether a response time is far enough above the baseline to count as an anomaly
+    pub fn is_anomaly(&self, response_time_ms: u64, deviations: f64) -> bool {
+        let response_time_ms = response_time_ms as f64;
+        self.samples >= Self::WARMUP
+            && response_time_ms > self.mean_ms + deviations * self.deviation_ms
+            && response_time_ms > self.mean_ms * Self::MIN_RATIO
+            && response_time_ms > self.mean_ms + Self::MIN_EXCESS_MS
+    }
+
+    pub fn update(&mut self, response_time_ms: u64) {
+        let difference = response_time_ms as f64 - self.mean_ms;
+        let increment = Self::WEIGHT * difference;
+        self.mean_ms += increment;
+        let variance = (1.0 - Self::WEIGHT) * (self.deviation_ms.powi(2) + difference * increment);
+        self.deviation_ms = variance.sqrt();
+        self.samples += 1;
+    }
+}
+
+/// Whether one check found the endpoint up, kept for uptime statistics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub at: DateTime<Utc>,
+    pub up: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_time_ms: Option<u64>,
+}
+
+/// Distribution of an endpoint's response times over the latency window
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyStats {
+    pub window_seconds: u64,
+    /// Checks with a response time within the window
+    pub count: usize,
+    pub min_ms: u64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+/// Response times up to `upper_ms`, above the previous bucket's; the last bucket has no limit
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramBucket {
+    pub upper_ms: Option<u64>,
+    pub count: usize,
+}
+
+/// Percentage of checks that found an endpoint up, `None` when there were none
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Uptime {
+    #[serde(rename = "24h")]
+    pub day: Option<f64>,
+    #[serde(rename = "7d")]
+    pub week: Option<f64>,
+    #[serde(rename = "30d")]
+    pub month: Option<f64>,
+}
+
+/// A change of an endpoint's status, e.g. from online to degraded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    pub at: DateTime<Utc>,
+    pub from: String,
+    pub to: String,
+    /// The endpoint went from up to down or back, rather than e.g. from online to degraded
+    pub up_down: bool,
+}
+
+/// One completed check of an endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckRecord {
+    pub checked_at: DateTime<Utc>,
+    pub status: EndpointStatus,
+    pub timings: CheckTimings,
+    /// Tor circuits that carried the probe, when a control port is configured
+    pub circuits: Vec<CircuitRecord>,
+    /// The reference onions were failing too
+    pub local_problem: bool,
+}
+
+/// A Tor circuit as reported by `CIRC` events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CircuitRecord {
+    pub id: String,
+    /// Circuit purpose such as `GENERAL`, `HS_CLIENT_INTRO` or `HS_CLIENT_REND`
+    pub purpose: Option<String>,
+    /// Last reported status, e.g. `BUILT` or `FAILED`
+    pub status: String,
+    /// Relays from the guard outwards
+    pub path: Vec<Relay>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Relay {
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+}
+
+impl Relay {
+    /// Parse a relay as written in control port paths: `$FINGERPRINT~nickname`,
+    /// `$FINGERPRINT=nickname` or `$FINGERPRINT`
+    pub fn parse(text: &str) -> Self {
+        let text = text.strip_prefix('$').unwrap_or(text);
+        match text.split_once(['~', '=']) {
+            Some((fingerprint, nickname)) => Self {
+                fingerprint: fingerprint.to_string(),
+                nickname: Some(nickname.to_string()),
+            },
+            None => Self {
+                fingerprint: text.to_string(),
+                nickname: None,
+            },
+        }
+    }
+
+    /// Nickname if known, otherwise the start of the fingerprint
+    pub fn label(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => nickname.clone(),
+            None => format!("${}", &self.fingerprint[..self.fingerprint.len().min(8)]),
+        }
+    }
+}
+
+/// What fetching an onion service's descriptor revealed after a failed check
+#[derive(Debug, Clone, Serialize)]
+pub struct DescriptorDiagnostics {
+    /// Whether any HSDir returned the descriptor
+    pub fetched: bool,
+    /// HSDirs asked for the descriptor, in the order Tor reported them
+    pub hsdirs: Vec<HsDirAttempt>,
+    /// Introduction points listed in the descriptor, if it could be decrypted
+    pub introduction_points: Option<usize>,
+    /// Why the fetch or decryption was incomplete
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl DescriptorDiagnostics {
+    /// One-line reading of the results for the failure details
+    pub fn summary(&self) -> String {
+        match (self.fetched, self.introduction_points) {
+            (true, Some(0)) => "Descriptor published without introduction points".to_string(),
+            (true, Some(count)) => format!(
+                "Descriptor published with {} introduction point{}: the service is publishing \
+                 but unreachable",
+                count,
+                if count == 1 { "" } else { "s" }
+            ),
+            (true, None) => {
+                "Descriptor published: the service is publishing but unreachable".to_string()
+            }
+            (false, _) => {
+                let not_found = self.hsdirs.iter().any(|attempt| {
+                    matches!(&attempt.result, HsDirResult::Failed { reason } if reason == "NOT_FOUND")
+                });
+                if not_found {
+                    "Descriptor not found on the HSDirs: the service is not publishing".to_string()
+                } else {
+                    "Descriptor fetch did not complete".to_string()
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HsDirAttempt {
+    /// Relay as reported by Tor, `$FINGERPRINT~nickname`
+    pub hsdir: String,
+    pub result: HsDirResult,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum HsDirResult {
+    /// Requested, but no answer arrived before the fetch timed out
+    Requested,
+    Received,
+    Failed {
+        reason: String,
+    },
+}
+
+/// Health of a proxy as seen by the monitor's per-cycle probe
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum ProxyHealth {
+    Unknown,
+    Up { response_time_ms: u64 },
+    Down { error: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyStatus {
+    pub name: String,
+    pub health: ProxyHealth,
+    pub last_check: Option<DateTime<Utc>>,
+}
+
+impl ProxyStatus {
+    pub fn is_down(&self) -> bool {
+        matches!(self.health, ProxyHealth::Down { .. })
+    }
+}
+
+/// Latest probe of a reference onion
+#[derive(Debug, Clone, Serialize)]
+pub struct ReferenceStatus {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub status: EndpointStatus,
+    pub last_check: Option<DateTime<Utc>>,
+}
+
+/// Tor's own view of its health, read from the control port
+#[derive(Debug, Clone, Serialize)]
+pub struct TorStatus {
+    /// Bootstrap percentage from `status/bootstrap-phase`
+    pub bootstrap_progress: Option<u8>,
+    pub bootstrap_summary: Option<String>,
+    /// Whether Tor believes it can build circuits (`status/circuit-established`)
+    pub circuit_established: Option<bool>,
+    /// `up` or `down`, from `network-liveness`
+    pub network_liveness: Option<String>,
+    /// Why the control port could not be queried
+    pub error: Option<String>,
+    pub last_check: DateTime<Utc>,
+}
+
+impl TorStatus {
+    pub fn unreachable(error: String) -> Self {
+        Self {
+            bootstrap_progress: None,
+            bootstrap_summary: None,
+            circuit_established: None,
+            network_liveness: None,
+            error: Some(error),
+            last_check: Utc::now(),
+        }
+    }
+
+    /// What is wrong with Tor, if anything
+    ///
+    /// When this is `None` Tor is bootstrapped, has circuits and a live network, so endpoint
+    /// failures are the endpoints' own.
+    pub fn problem(&self) -> Option<String> {
+        if let Some(error) = &self.error {
+            return Some(format!("Control port query failed: {}", error));
+        }
+        match self.bootstrap_progress {
+            Some(100) => {}
+            Some(progress) => {
+                return Some(format!(
+                    "Bootstrapping {}% ({})",
+                    progress,
+                    self.bootstrap_summary.as_deref().unwrap_or("in progress")
+                ));
+            }
+            None => return Some("Bootstrap status unknown".to_string()),
+        }
+        if self.circuit_established == Some(false) {
+            return Some("No circuits established".to_string());
+        }
+        if self.network_liveness.as_deref() == Some("down") {
+            return Some("Network is down".to_string());
+        }
+        None
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.problem().is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StatusStore {
+    pub endpoints: HashMap<String, EndpointInfo>,
+    pub proxies: Vec<ProxyStatus>,
+    /// Latest control port reading, `None` when no control port is configured
+    pub tor: Option<TorStatus>,
+    /// Onion address the dashboard is published at, while Tor has the service
+    pub dashboard_onion: Option<String>,
+    pub references: Vec<ReferenceStatus>,
+    pub scheduler: SchedulerStats,
+}
+
+/// How well monitoring keeps to its schedule, since it started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SchedulerStats {
+    pub interval_seconds: u64,
+    /// Intervals whose endpoint checks have all finished, counting only intervals with checks
+    pub cycles: u64,
+    /// How long the checks due within the last such interval took to finish, from the start of
+    /// the interval, or every check in a single run
+    pub last_cycle_ms: Option<u64>,
+    /// Intervals whose checks took longer than the interval to finish
+    pub overruns: u64,
+    /// Checks that started more than a second after they were due
+    pub late_checks: u64,
+    pub max_lateness_ms: u64,
+    /// Checks that were dropped because the previous check of the endpoint was still running or
+    /// the scheduler was too far behind
+    pub skipped_checks: u64,
+    /// Last overrun, late or skipped check
+    pub last_problem: Option<DateTime<Utc>>,
+}
+
+impl StatusStore {
+    /// Median response time of the reference onions that are up
+    pub fn reference_latency_ms(&self) -> Option<u64> {
+        let mut response_times: Vec<u64> = self
+            .references
+            .iter()
+            .filter_map(|reference| reference.status.response_time_ms())
+            .collect();
+        response_times.sort_unstable();
+        response_times.get(response_times.len() / 2).copied()
+    }
+
+    /// Every reference onion failed its last probe, so failures are likely on our side
+    pub fn local_problem(&self) -> bool {
+        !self.references.is_empty()
+            && self
+                .references
+                .iter()
+                .all(|reference| reference.status.is_up() == Some(false))
+    }
+}